@@ -0,0 +1,356 @@
+/*!
+ * Producer side of the ESP-NOW receive and send callbacks.
+ * Kept free of esp-idf so it builds and is tested on the host: `cargo test` in this directory.
+*/
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendResult {
+    Delivered,
+    // MAC layer delivery failed, resend
+    Retry,
+    // Not delivered, or not acknowledged by the node, after retries
+    Failed,
+}
+
+/**
+ * Events published by the callbacks
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallbackEvent {
+    // Frame from a node, signature already checked and stripped
+    Upstream(Vec<u8>),
+    // Frame rejected by the signature check, MAC of the sender
+    Spoof([u8; 6]),
+    SendResult { device_no: u8, result: SendResult },
+}
+
+/**
+ * Where the ESPNOW callbacks put their events. Must never block, the callbacks run in the WiFi task.
+*/
+pub trait Sink: Send + Sync + 'static {
+    // False when the event was dropped
+    fn try_publish(&self, event: CallbackEvent) -> bool;
+}
+
+/**
+ * Producer side of the ESPNOW receive callback, owned by the callback registered with the Espnow instance
+*/
+pub struct RecvContext<S: Sink> {
+    sink: S,
+    drops: AtomicU32,
+}
+
+impl<S: Sink> RecvContext<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, drops: AtomicU32::new(0) }
+    }
+
+    /**
+     * Frame from a node, signature already checked and stripped
+    */
+    pub fn upstream(&self, data: &[u8]) -> bool {
+        self.publish(CallbackEvent::Upstream(data.to_vec()))
+    }
+
+    /**
+     * Frame rejected by the signature check, MAC of the sender
+    */
+    pub fn spoof(&self, mac_addr: &[u8]) -> bool {
+        let mut mac = [0u8; 6];
+        let sz = mac_addr.len().min(6);
+        mac[..sz].copy_from_slice(&mac_addr[..sz]);
+        self.publish(CallbackEvent::Spoof(mac))
+    }
+
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, event: CallbackEvent) -> bool {
+        let delivered = self.sink.try_publish(event);
+        if !delivered {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+        delivered
+    }
+}
+
+/**
+ * Producer side of the ESPNOW send callback, decides between retry and failure.
+ * Frames to different nodes are in flight together, so each node has its own retry count.
+ * Results of liveness probes are neither retried nor published, an offline node is expected to miss them.
+*/
+pub struct SendContext<S: Sink> {
+    sink: S,
+    max_retry: usize,
+    retry_counts: Vec<AtomicUsize>,
+    // Set while a liveness probe to the node waits for its send result
+    probes: Vec<AtomicBool>,
+    drops: AtomicU32,
+}
+
+impl<S: Sink> SendContext<S> {
+    pub fn new(sink: S, node_count: usize, max_retry: usize) -> Self {
+        Self {
+            sink,
+            max_retry,
+            retry_counts: (0..node_count).map(|_| AtomicUsize::new(0)).collect(),
+            probes: (0..node_count).map(|_| AtomicBool::new(false)).collect(),
+            drops: AtomicU32::new(0),
+        }
+    }
+
+    /**
+     * The next send result of the node belongs to a liveness probe. Set before sending, cleared when the send fails.
+    */
+    pub fn mark_probe(&self, device_no: u8, probe: bool) {
+        if let Some(p) = self.probes.get(device_no as usize) {
+            p.store(probe, Ordering::Relaxed);
+        }
+    }
+
+    /**
+     * MAC layer ACK received
+    */
+    pub fn delivered(&self, device_no: u8) -> SendResult {
+        if let Some(count) = self.retry_counts.get(device_no as usize) {
+            count.store(0, Ordering::Relaxed);
+        }
+        if self.take_probe(device_no) {
+            return SendResult::Delivered;
+        }
+        self.publish(device_no, SendResult::Delivered)
+    }
+
+    /**
+     * MAC layer delivery failed: retry up to max_retry times, then report the failure.
+     * The count goes back to 0 on failure in the same atomic update.
+    */
+    pub fn failed(&self, device_no: u8) -> SendResult {
+        let count = match self.retry_counts.get(device_no as usize) {
+            Some(count) => count,
+            None => return self.publish(device_no, SendResult::Failed),
+        };
+        // Probe failed, the retry count of the node's own frames is left alone
+        if self.take_probe(device_no) {
+            return SendResult::Failed;
+        }
+        let max_retry = self.max_retry;
+        let prev = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            Some(if n < max_retry { n + 1 } else { 0 })
+        }).unwrap_or_default();
+        let result = if prev < max_retry { SendResult::Retry } else { SendResult::Failed };
+        self.publish(device_no, result)
+    }
+
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    fn take_probe(&self, device_no: u8) -> bool {
+        self.probes.get(device_no as usize).map_or(false, |p| p.swap(false, Ordering::Relaxed))
+    }
+
+    fn publish(&self, device_no: u8, result: SendResult) -> SendResult {
+        if !self.sink.try_publish(CallbackEvent::SendResult { device_no, result }) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const PRODUCERS: usize = 8;
+    const FRAMES: usize = 1000;
+
+    // Bounded queue standing in for a bus subscriber
+    struct TestSink {
+        capacity: usize,
+        events: Mutex<VecDeque<CallbackEvent>>,
+    }
+
+    impl TestSink {
+        fn new(capacity: usize) -> Arc<Self> {
+            Arc::new(Self { capacity, events: Mutex::new(VecDeque::new()) })
+        }
+    }
+
+    impl Sink for Arc<TestSink> {
+        fn try_publish(&self, event: CallbackEvent) -> bool {
+            let mut events = self.events.lock().unwrap();
+            if events.len() >= self.capacity {
+                return false;
+            }
+            events.push_back(event);
+            true
+        }
+    }
+
+    // Frames [producer, seq hi, seq lo] pushed from every producer at once
+    fn hammer(context: &Arc<RecvContext<Arc<TestSink>>>) -> usize {
+        let handles: Vec<_> = (0..PRODUCERS).map(|p| {
+            let context = context.clone();
+            thread::spawn(move || {
+                let mut delivered = 0;
+                for seq in 0..FRAMES {
+                    if context.upstream(&[p as u8, (seq >> 8) as u8, seq as u8]) {
+                        delivered += 1;
+                    }
+                }
+                delivered
+            })
+        }).collect();
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    }
+
+    // Sequence numbers received from each producer
+    fn received(sink: &TestSink) -> Vec<Vec<usize>> {
+        let mut seqs = vec![Vec::new(); PRODUCERS];
+        for event in sink.events.lock().unwrap().iter() {
+            match event {
+                CallbackEvent::Upstream(frame) => seqs[frame[0] as usize].push(((frame[1] as usize) << 8) | frame[2] as usize),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        seqs
+    }
+
+    #[test]
+    fn recv_keeps_every_frame_in_order() {
+        let sink = TestSink::new(PRODUCERS * FRAMES);
+        let context = Arc::new(RecvContext::new(sink.clone()));
+
+        assert_eq!(hammer(&context), PRODUCERS * FRAMES);
+        assert_eq!(context.drops(), 0);
+        for seqs in received(&sink) {
+            assert_eq!(seqs, (0..FRAMES).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn recv_counts_overflow() {
+        let capacity = PRODUCERS * FRAMES / 3;
+        let sink = TestSink::new(capacity);
+        let context = Arc::new(RecvContext::new(sink.clone()));
+
+        let delivered = hammer(&context);
+        assert_eq!(delivered, capacity);
+        assert_eq!(context.drops() as usize, PRODUCERS * FRAMES - capacity);
+
+        // Dropped frames leave gaps, what got through is neither duplicated nor reordered
+        let seqs = received(&sink);
+        assert_eq!(seqs.iter().map(|s| s.len()).sum::<usize>(), capacity);
+        for seqs in seqs {
+            assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn recv_spoof_keeps_mac() {
+        let sink = TestSink::new(1);
+        let context = RecvContext::new(sink.clone());
+
+        assert!(context.spoof(&[1, 2, 3, 4, 5, 6]));
+        assert!(!context.spoof(&[1, 2, 3, 4, 5, 6]));
+        assert_eq!(context.drops(), 1);
+        assert_eq!(sink.events.lock().unwrap()[0], CallbackEvent::Spoof([1, 2, 3, 4, 5, 6]));
+    }
+
+    #[test]
+    fn send_retries_then_fails() {
+        let sink = TestSink::new(16);
+        let context = SendContext::new(sink.clone(), 3, 2);
+
+        assert_eq!(context.failed(1), SendResult::Retry);
+        assert_eq!(context.failed(1), SendResult::Retry);
+        assert_eq!(context.failed(1), SendResult::Failed);
+        // Counter starts over after a failure or a delivery
+        assert_eq!(context.failed(1), SendResult::Retry);
+        assert_eq!(context.delivered(1), SendResult::Delivered);
+        assert_eq!(context.failed(1), SendResult::Retry);
+        assert_eq!(sink.events.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn send_counts_retries_per_node() {
+        let sink = TestSink::new(16);
+        let context = SendContext::new(sink.clone(), 3, 1);
+
+        assert_eq!(context.failed(1), SendResult::Retry);
+        // Delivery to another node doesn't reset node 1
+        assert_eq!(context.delivered(2), SendResult::Delivered);
+        assert_eq!(context.failed(1), SendResult::Failed);
+        assert_eq!(context.failed(2), SendResult::Retry);
+        // Unknown device can't be retried
+        assert_eq!(context.failed(9), SendResult::Failed);
+    }
+
+    #[test]
+    fn send_probe_is_not_retried_or_published() {
+        let sink = TestSink::new(16);
+        let context = SendContext::new(sink.clone(), 3, 2);
+
+        assert_eq!(context.failed(1), SendResult::Retry);
+        context.mark_probe(1, true);
+        assert_eq!(context.failed(1), SendResult::Failed);
+        // Only the probe's result is swallowed, retries of the node's frame go on where they were
+        assert_eq!(context.failed(1), SendResult::Retry);
+        assert_eq!(context.failed(1), SendResult::Failed);
+        context.mark_probe(2, true);
+        assert_eq!(context.delivered(2), SendResult::Delivered);
+        // Probe not sent, the next result is the node's own
+        context.mark_probe(2, true);
+        context.mark_probe(2, false);
+        assert_eq!(context.failed(2), SendResult::Retry);
+
+        let events = sink.events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|e| !matches!(e, CallbackEvent::SendResult { result: SendResult::Delivered, .. })));
+    }
+
+    #[test]
+    fn send_fails_once_per_round_from_threads() {
+        let sink = TestSink::new(PRODUCERS * FRAMES);
+        let context = Arc::new(SendContext::new(sink.clone(), 2, 3));
+
+        let handles: Vec<_> = (0..PRODUCERS).map(|_| {
+            let context = context.clone();
+            thread::spawn(move || {
+                (0..FRAMES).filter(|_| context.failed(1) == SendResult::Failed).count()
+            })
+        }).collect();
+        let failed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        // Every 4th failure reports, no update is lost between the threads
+        assert_eq!(failed, PRODUCERS * FRAMES / 4);
+    }
+
+    #[test]
+    fn send_counts_overflow_from_threads() {
+        let sink = TestSink::new(100);
+        let context = Arc::new(SendContext::new(sink.clone(), PRODUCERS, 3));
+
+        let handles: Vec<_> = (0..PRODUCERS).map(|p| {
+            let context = context.clone();
+            thread::spawn(move || {
+                for _ in 0..FRAMES {
+                    context.delivered(p as u8);
+                }
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(sink.events.lock().unwrap().len(), 100);
+        assert_eq!(context.drops() as usize, PRODUCERS * FRAMES - 100);
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{bail, Result};
+use log::*;
+use hmac::{Hmac, Mac};
+use num_derive::FromPrimitive;
+use rosc::{OscMessage, OscPacket, OscType};
+use sha2::Sha256;
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Shortest accepted truncated HMAC
+const HMAC_MIN_LEN: usize = 16;
+// Accepted timestamps are persisted in blocks, NVS is written when a timestamp reaches the saved mark.
+// After a reboot, timestamps have to pass the saved mark, one second ahead with a ms clock.
+const AUTH_TIMESTAMP_MARGIN: i64 = 1000;
+
+// Commands that can reboot the station, redirect replies or change peers/config
+// /estop itself is open to everyone, only releasing it is privileged
+pub const PRIVILEGED: [&str; 15] = [
+    "/reset", "/setdestip", "/setip", "/setradio", "/protocol", "/appack", "/setpmk", "/setlmk", "/clearlmk", "/setsigkey", "/clearsigkey",
+    "/estop/release", "/loglevel", "/capture", "/capture/stream",
+];
+// Commands only accepted when allow-list or HMAC key is configured
+pub const CONFIG_COMMANDS: [&str; 5] = ["/setpmk", "/setlmk", "/clearlmk", "/setsigkey", "/clearsigkey"];
+
+#[derive(FromPrimitive, Clone, Copy, Debug)]
+pub enum Denied {
+    Address = 1,
+    MissingHmac = 2,
+    BadHmac = 3,
+    Replay = 4,
+    Disabled = 5,
+}
+
+impl Denied {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Denied::Address => "address",
+            Denied::MissingHmac => "missing hmac",
+            Denied::BadHmac => "bad hmac",
+            Denied::Replay => "replay",
+            Denied::Disabled => "disabled",
+        }
+    }
+}
+
+/**
+ * Security layer for privileged OSC commands
+ * - allow-list of source subnets
+ * - HMAC-SHA256 over the message with its last two arguments: [timestamp] [hmac]
+ *   HMAC is calculated over the OSC encoded message without the hmac argument.
+ *   Timestamp (int or long) has to increase on every command, which rejects replays.
+ *   A mark above the last accepted timestamp is kept in NVS, so commands recorded before a reboot stay rejected.
+*/
+pub struct Auth {
+    allow_list: Vec<(u32, u32)>,
+    hmac_key: Option<&'static [u8]>,
+    last_timestamp: i64,
+    // Mark saved in NVS, last accepted timestamp plus the margin
+    saved_timestamp: i64,
+    config: Arc<Mutex<Config>>,
+}
+
+impl Auth {
+    /**
+     * allow_list: comma separated addresses or subnets, e.g. "192.168.1.0/24,10.0.0.5"
+    */
+    pub fn new(allow_list: Option<&str>, hmac_key: Option<&'static str>, config: Arc<Mutex<Config>>) -> Result<Self> {
+        let mut subnets = vec![];
+        for entry in allow_list.unwrap_or("").split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (ip, prefix) = match entry.split_once('/') {
+                Some((ip, prefix)) => (ip, prefix.parse::<u32>()?),
+                None => (entry, 32),
+            };
+            if prefix > 32 {
+                bail!("Invalid subnet {entry}");
+            }
+            let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+            subnets.push((u32::from(Ipv4Addr::from_str(ip)?) & mask, mask));
+        }
+
+        // Commands accepted before the reboot were all below the saved mark
+        let saved_timestamp = config.lock().unwrap().auth_timestamp().unwrap_or(i64::MIN);
+        Ok(Self {
+            allow_list: subnets,
+            hmac_key: hmac_key.map(|k| k.as_bytes()),
+            last_timestamp: saved_timestamp,
+            saved_timestamp,
+            config,
+        })
+    }
+
+    pub fn configured(&self) -> bool {
+        !self.allow_list.is_empty() || self.hmac_key.is_some()
+    }
+
+    fn allowed(&self, src: Ipv4Addr) -> bool {
+        let src = u32::from(src);
+        self.allow_list.is_empty() || self.allow_list.iter().any(|(net, mask)| src & mask == *net)
+    }
+
+    /**
+     * Check privileged command. On success timestamp and hmac arguments are removed from the message.
+    */
+    pub fn check(&mut self, src: Ipv4Addr, msg: &mut OscMessage) -> Result<(), Denied> {
+        if CONFIG_COMMANDS.contains(&msg.addr.as_str()) && !self.configured() {
+            return Err(Denied::Disabled);
+        }
+        if !self.allowed(src) {
+            return Err(Denied::Address);
+        }
+        let key = match self.hmac_key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let len = msg.args.len();
+        if len < 2 {
+            return Err(Denied::MissingHmac);
+        }
+        let timestamp = match msg.args[len - 2] {
+            OscType::Int(t) => t as i64,
+            OscType::Long(t) => t,
+            _ => return Err(Denied::MissingHmac),
+        };
+        let tag = match msg.args.pop() {
+            Some(OscType::Blob(tag)) if tag.len() >= HMAC_MIN_LEN => tag,
+            _ => return Err(Denied::MissingHmac),
+        };
+
+        let signed = rosc::encoder::encode(&OscPacket::Message(msg.clone())).map_err(|_| Denied::BadHmac)?;
+        let mut mac = HmacSha256::new_from_slice(key).map_err(|_| Denied::BadHmac)?;
+        mac.update(&signed);
+        mac.verify_truncated_left(&tag).map_err(|_| Denied::BadHmac)?;
+
+        if timestamp <= self.last_timestamp {
+            return Err(Denied::Replay);
+        }
+        self.last_timestamp = timestamp;
+        if timestamp >= self.saved_timestamp {
+            let mark = timestamp.saturating_add(AUTH_TIMESTAMP_MARGIN);
+            match self.config.lock().unwrap().set_auth_timestamp(mark) {
+                Ok(_) => self.saved_timestamp = mark,
+                Err(e) => error!("Auth: failed to save timestamp: {e}"),
+            }
+        }
+        msg.args.pop();
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{bail, Result};
+use log::*;
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use espnow_callback::{CallbackEvent, Sink};
+pub use espnow_callback::SendResult;
+
+use crate::auth::Denied;
+use crate::liveness::NodeState;
+
+/**
+ * Events passed between the threads.
+ * Publishers don't know who receives them, a new sink (web UI, MQTT...) only has to subscribe.
+*/
+#[derive(Clone, Debug)]
+pub enum Event {
+    // v1 frame [header, device no, payload...] to a node, from OSC, MIDI or DMX
+    Downstream(Vec<u8>, Priority),
+    // Frame received from a node, signature already checked and stripped
+    Upstream(Vec<u8>),
+    // Node reply decoded and de-duplicated by OscSender: [header, device no, payload...]
+    Reply(Vec<u8>),
+    // Result of an ESPNOW send
+    SendResult { device_no: u8, result: SendResult },
+    ConfigChange(ConfigChange),
+    Indicator(Indicator),
+    // Privileged OSC command rejected
+    Denied { src: Ipv4Addr, reason: Denied, addr: String },
+    // ESPNOW frame rejected by signature check, MAC of the sender
+    Spoof([u8; 6]),
+    // E-stop latched (true) or released (false) from OSC
+    Estop(bool),
+    // Node acknowledged the current e-stop state
+    EstopAck(u8),
+    // Nodes that didn't acknowledge the e-stop state in time
+    EstopMissing(Vec<u8>),
+    // Node went online or offline
+    NodeState { device_no: u8, state: NodeState },
+    // Status query from OSC, answered by OscSender
+    Query(Query),
+}
+
+/**
+ * High priority frames jump the queues, and skip rate limiting and coalescing of the scheduler
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Clone, Debug)]
+pub enum ConfigChange {
+    // Destination address in use, resolved from the destination host
+    DestIp(Ipv4Addr),
+    // Destination set by /setdestip, IPv4 address or hostname
+    DestHost(String),
+    // ESPNOW keys of the peer changed, 0 for PMK
+    PeerKeys(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Query {
+    // State of every node
+    Nodes,
+    // Station counters and health
+    Stats,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Indicator {
+    // ESPNOW frame sent
+    Espnow,
+    // OSC message sent
+    Osc,
+}
+
+/**
+ * What happens to an event when the subscriber's queue is full
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    DropNewest,
+    DropOldest,
+    // Wait for the subscriber to make room, then drop the new event.
+    // ESPNOW callbacks never block, they drop the new event instead.
+    Block(Duration),
+}
+
+type Filter = fn(&Event) -> bool;
+
+fn is_urgent(event: &Event) -> bool {
+    matches!(event, Event::Downstream(_, Priority::High))
+}
+
+struct Queue {
+    name: &'static str,
+    capacity: usize,
+    filter: Filter,
+    policy: Mutex<Overflow>,
+    events: Mutex<VecDeque<Event>>,
+    cond: Condvar,
+    space: Condvar,
+    drops: AtomicU32,
+    high_water: AtomicU32,
+}
+
+impl Queue {
+    fn push(&self, event: Event, may_block: bool) -> bool {
+        let policy = *self.policy.lock().unwrap();
+        let mut events = self.events.lock().unwrap();
+        let urgent = is_urgent(&event);
+        // Urgent event takes the place of the newest normal event
+        if urgent && events.len() >= self.capacity {
+            if let Some(pos) = events.iter().rposition(|e| !is_urgent(e)) {
+                events.remove(pos);
+                self.dropped();
+            }
+        }
+        if events.len() >= self.capacity {
+            match policy {
+                Overflow::Block(timeout) if may_block => {
+                    events = self.space.wait_timeout_while(events, timeout, |e| e.len() >= self.capacity).unwrap().0;
+                }
+                Overflow::DropOldest => {
+                    events.pop_front();
+                    self.dropped();
+                }
+                _ => {}
+            }
+            if events.len() >= self.capacity {
+                self.dropped();
+                return false;
+            }
+        }
+        if urgent {
+            // Behind the urgent events already queued, ahead of the others
+            let pos = events.iter().position(|e| !is_urgent(e)).unwrap_or(events.len());
+            events.insert(pos, event);
+        }
+        else {
+            events.push_back(event);
+        }
+        self.high_water.fetch_max(events.len() as u32, Ordering::Relaxed);
+        self.cond.notify_one();
+        true
+    }
+
+    fn dropped(&self) {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+        error!("{}:Buffer Overflow!", self.name);
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<Arc<Queue>>> = Mutex::new(Vec::new());
+
+/**
+ * Subscribe to the events matching the filter. Subscribe before the publishing threads start, events are not kept for late subscribers.
+*/
+pub fn subscribe(name: &'static str, capacity: usize, policy: Overflow, filter: Filter) -> Subscriber {
+    let queue = Arc::new(Queue {
+        name,
+        capacity,
+        filter,
+        policy: Mutex::new(policy),
+        events: Mutex::new(VecDeque::with_capacity(capacity)),
+        cond: Condvar::new(),
+        space: Condvar::new(),
+        drops: AtomicU32::new(0),
+        high_water: AtomicU32::new(0),
+    });
+    SUBSCRIBERS.lock().unwrap().push(queue.clone());
+    Subscriber(queue)
+}
+
+/**
+ * Override overflow policy of the queues: "espnow=block:10,osc=drop-oldest,led=drop-newest", block timeout in ms
+*/
+pub fn configure(policies: Option<&str>) -> Result<()> {
+    for entry in policies.unwrap_or("").split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (name, policy) = match entry.split_once('=') {
+            Some(pair) => pair,
+            None => bail!("Invalid queue policy {entry}"),
+        };
+        let policy = match policy.trim() {
+            "drop-newest" => Overflow::DropNewest,
+            "drop-oldest" => Overflow::DropOldest,
+            p => match p.strip_prefix("block:") {
+                Some(ms) => Overflow::Block(Duration::from_millis(ms.parse::<u64>()?)),
+                None => bail!("Invalid queue policy {entry}"),
+            },
+        };
+        let subscribers = SUBSCRIBERS.lock().unwrap();
+        match subscribers.iter().find(|q| q.name == name.trim()) {
+            Some(queue) => *queue.policy.lock().unwrap() = policy,
+            None => bail!("Unknown queue {name}"),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Deliver the event to every subscriber whose filter matches, applying each queue's overflow policy.
+ * Returns false when a subscriber's queue was full and the event was dropped for it.
+*/
+pub fn publish(event: Event) -> bool {
+    publish_with(event, true)
+}
+
+/**
+ * Publish without blocking, for the ESPNOW callbacks running in the WiFi task
+*/
+pub fn try_publish(event: Event) -> bool {
+    publish_with(event, false)
+}
+
+/**
+ * The bus as the sink of the ESPNOW callbacks
+*/
+pub struct CallbackSink;
+
+impl Sink for CallbackSink {
+    fn try_publish(&self, event: CallbackEvent) -> bool {
+        try_publish(match event {
+            CallbackEvent::Upstream(frame) => Event::Upstream(frame),
+            CallbackEvent::Spoof(mac) => Event::Spoof(mac),
+            CallbackEvent::SendResult { device_no, result } => Event::SendResult { device_no, result },
+        })
+    }
+}
+
+fn publish_with(event: Event, may_block: bool) -> bool {
+    match &event {
+        Event::Downstream(..) => DOWNSTREAM_LATENCY.stamp(),
+        Event::Upstream(_) => UPSTREAM_LATENCY.stamp(),
+        _ => {}
+    }
+
+    // Don't hold the subscriber list while blocked on a full queue
+    let queues: Vec<Arc<Queue>> = SUBSCRIBERS.lock().unwrap().iter().filter(|q| (q.filter)(&event)).cloned().collect();
+    let mut delivered = true;
+    for queue in queues {
+        delivered &= queue.push(event.clone(), may_block);
+    }
+    delivered
+}
+
+pub struct QueueStats {
+    pub name: &'static str,
+    pub capacity: usize,
+    pub len: usize,
+    pub drops: u32,
+    pub high_water: u32,
+}
+
+pub fn stats() -> Vec<QueueStats> {
+    SUBSCRIBERS.lock().unwrap().iter().map(|q| QueueStats {
+        name: q.name,
+        capacity: q.capacity,
+        len: q.events.lock().unwrap().len(),
+        drops: q.drops.load(Ordering::Relaxed),
+        high_water: q.high_water.load(Ordering::Relaxed),
+    }).collect()
+}
+
+pub struct Subscriber(Arc<Queue>);
+
+impl Subscriber {
+    pub fn try_recv(&self) -> Option<Event> {
+        let event = self.0.events.lock().unwrap().pop_front();
+        if event.is_some() {
+            self.0.space.notify_one();
+        }
+        event
+    }
+
+    /**
+     * Block until an event is queued or timeout, the event stays queued for try_recv
+    */
+    pub fn wait(&self, timeout: Duration) {
+        let events = self.0.events.lock().unwrap();
+        let _ = self.0.cond.wait_timeout_while(events, timeout, |e| e.is_empty()).unwrap();
+    }
+}
+
+fn now_us() -> u32 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u32 }
+}
+
+/**
+ * Time stamp of the last frame entering the bridge, to log the latency until it leaves
+*/
+pub struct Latency {
+    at_us: AtomicU32,
+}
+
+impl Latency {
+    pub const fn new() -> Self {
+        Self { at_us: AtomicU32::new(0) }
+    }
+
+    pub fn stamp(&self) {
+        self.at_us.store(now_us(), Ordering::Relaxed);
+    }
+
+    pub fn elapsed_us(&self) -> u32 {
+        now_us().wrapping_sub(self.at_us.load(Ordering::Relaxed))
+    }
+}
+
+// OSC/MIDI/DMX received -> ESPNOW sent
+pub static DOWNSTREAM_LATENCY: Latency = Latency::new();
+// ESPNOW received -> OSC sent
+pub static UPSTREAM_LATENCY: Latency = Latency::new();
@@ -0,0 +1,318 @@
+use log::*;
+
+use std::collections::VecDeque;
+use std::net::{SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::espnow::{device_no, NODE_ADDRESSES};
+
+/*
+ * Capture record, pcap link type LINKTYPE_USER0 (147), decoded by tools/espnow_station.lua
+ * [version, kind, direction, status, address (6 bytes), payload...]
+ * address is the peer MAC for ESP-NOW, IPv4 address and port (BE) for OSC.
+*/
+const CAPTURE_VERSION: u8 = 1;
+const LINKTYPE_USER0: u32 = 147;
+const RECORD_HEADER_LEN: usize = 10;
+const SNAPLEN: u32 = 2048;
+
+// Bytes and records kept in the ring buffer, oldest records are dropped first.
+// Allocated when capture starts, so recording from the WiFi callbacks doesn't allocate.
+const CAPTURE_BUFFER_LEN: usize = 32 * 1024;
+const CAPTURE_MAX_RECORDS: usize = 512;
+// Records copied per lock while building the pcap file
+const PCAP_CHUNK_RECORDS: usize = 16;
+const CAPTURE_IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Osc = 0,
+    Espnow = 1,
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    // To the station
+    In = 0,
+    // From the station
+    Out = 1,
+}
+
+#[derive(Clone, Copy)]
+pub enum Status {
+    None = 0,
+    Delivered = 1,
+    Failed = 2,
+    // Signature check failed
+    Rejected = 3,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    seq: u32,
+    at_us: i64,
+    // Position in the byte stream of the ring, counted from the start of the capture
+    start: usize,
+    len: usize,
+}
+
+struct Ring {
+    records: VecDeque<Record>,
+    bytes: VecDeque<u8>,
+    // Position of the first byte still in the ring
+    start: usize,
+    next_seq: u32,
+}
+
+impl Ring {
+    fn clear(&mut self) {
+        self.records.clear();
+        self.bytes.clear();
+        self.start = 0;
+    }
+
+    fn data(&self, record: &Record) -> std::collections::vec_deque::Iter<'_, u8> {
+        let from = record.start - self.start;
+        self.bytes.range(from..from + record.len)
+    }
+
+    /**
+     * Index of the record with the sequence, or the oldest one when it was dropped already
+    */
+    fn index_from(&self, seq: u32) -> Option<usize> {
+        let front = self.records.front()?.seq;
+        let offset = seq.wrapping_sub(front);
+        let index = if offset < u32::MAX / 2 { offset as usize } else { 0 };
+        (index < self.records.len()).then_some(index)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RING: Mutex<Ring> = Mutex::new(Ring { records: VecDeque::new(), bytes: VecDeque::new(), start: 0, next_seq: 0 });
+static RING_COND: Condvar = Condvar::new();
+// Records dropped from the ring before download or streaming
+pub static CAPTURE_DROPPED: AtomicU32 = AtomicU32::new(0);
+// UDP stream destination
+static STREAM: Mutex<Option<SocketAddrV4>> = Mutex::new(None);
+// Encrypted peers, their ESP-NOW payload is not recorded
+#[allow(clippy::declare_interior_mutable_const)]
+const REDACTED_INIT: AtomicBool = AtomicBool::new(false);
+static REDACTED: [AtomicBool; NODE_ADDRESSES.len()] = [REDACTED_INIT; NODE_ADDRESSES.len()];
+
+fn now_us() -> i64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() }
+}
+
+/**
+ * Start capturing, or stop and clear the buffer
+*/
+pub fn enable(enabled: bool) {
+    info!("Capture {}", if enabled { "started" } else { "stopped" });
+    let mut ring = RING.lock().unwrap();
+    if enabled {
+        ring.records.reserve_exact(CAPTURE_MAX_RECORDS);
+        ring.bytes.reserve_exact(CAPTURE_BUFFER_LEN);
+    }
+    else {
+        ring.clear();
+        ring.records.shrink_to_fit();
+        ring.bytes.shrink_to_fit();
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/**
+ * Don't record ESP-NOW payload of the peer, set for peers with LMK
+*/
+pub fn redact(device_no: usize, redacted: bool) {
+    if let Some(r) = REDACTED.get(device_no) {
+        r.store(redacted, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Stream records as pcap over UDP, None stops
+*/
+pub fn stream(dest: Option<SocketAddrV4>) {
+    info!("Capture stream to {:?}", dest);
+    *STREAM.lock().unwrap() = dest;
+    RING_COND.notify_one();
+}
+
+/**
+ * may_block is false in the WiFi callbacks, the record is dropped when the ring is busy
+*/
+fn record(kind: Kind, direction: Direction, status: Status, address: [u8; 6], payload: &[u8], may_block: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let payload = &payload[..payload.len().min(SNAPLEN as usize - RECORD_HEADER_LEN)];
+    let len = RECORD_HEADER_LEN + payload.len();
+    let at_us = now_us();
+
+    let mut ring = if may_block {
+        RING.lock().unwrap()
+    }
+    else {
+        match RING.try_lock() {
+            Ok(ring) => ring,
+            Err(_) => {
+                CAPTURE_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    };
+    // Capture stopped in the meantime, the buffer is freed
+    if ring.bytes.capacity() < CAPTURE_BUFFER_LEN {
+        return;
+    }
+    while ring.bytes.len() + len > CAPTURE_BUFFER_LEN || ring.records.len() >= CAPTURE_MAX_RECORDS {
+        match ring.records.pop_front() {
+            Some(old) => {
+                ring.bytes.drain(..old.len);
+                ring.start += old.len;
+                CAPTURE_DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+            None => break,
+        }
+    }
+    let seq = ring.next_seq;
+    ring.next_seq = seq.wrapping_add(1);
+    let start = ring.start + ring.bytes.len();
+    ring.bytes.extend([CAPTURE_VERSION, kind as u8, direction as u8, status as u8]);
+    ring.bytes.extend(address);
+    ring.bytes.extend(payload.iter().copied());
+    ring.records.push_back(Record { seq, at_us, start, len });
+    RING_COND.notify_one();
+}
+
+pub fn osc(direction: Direction, addr: SocketAddrV4, payload: &[u8]) {
+    let mut address = [0u8; 6];
+    address[..4].copy_from_slice(&addr.ip().octets());
+    address[4..].copy_from_slice(&addr.port().to_be_bytes());
+    record(Kind::Osc, direction, Status::None, address, payload, true);
+}
+
+/**
+ * Called from the ESPNOW callbacks too, never blocks
+*/
+pub fn espnow(direction: Direction, status: Status, mac: &[u8], payload: &[u8]) {
+    let mut address = [0u8; 6];
+    let len = mac.len().min(6);
+    address[..len].copy_from_slice(&mac[..len]);
+    let redacted = REDACTED[device_no(mac) as usize].load(Ordering::Relaxed);
+    record(Kind::Espnow, direction, status, address, if redacted { &[] } else { payload }, false);
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Time zone and accuracy
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    header
+}
+
+/**
+ * pcap record, time stamped from boot as there is no clock
+*/
+fn pcap_record<'a>(record: &Record, data: impl Iterator<Item = &'a u8>, out: &mut Vec<u8>) {
+    out.extend_from_slice(&((record.at_us / 1_000_000) as u32).to_le_bytes());
+    out.extend_from_slice(&((record.at_us % 1_000_000) as u32).to_le_bytes());
+    out.extend_from_slice(&(record.len as u32).to_le_bytes());
+    out.extend_from_slice(&(record.len as u32).to_le_bytes());
+    out.extend(data);
+}
+
+/**
+ * Ring buffer as a pcap file, for the HTTP download.
+ * Records are copied a few at a time, so the callbacks recording meanwhile rarely find the ring locked.
+ * Records captured after the download started are left out.
+*/
+pub fn pcap() -> Vec<u8> {
+    let mut out = pcap_header();
+    let (mut next_seq, end_seq) = {
+        let ring = RING.lock().unwrap();
+        out.reserve(ring.bytes.len() + ring.records.len() * 16);
+        (ring.records.front().map_or(ring.next_seq, |r| r.seq), ring.next_seq)
+    };
+    while next_seq != end_seq {
+        let ring = RING.lock().unwrap();
+        let index = match ring.index_from(next_seq) {
+            Some(index) => index,
+            None => break,
+        };
+        for record in ring.records.iter().skip(index).take(PCAP_CHUNK_RECORDS) {
+            // Captured after the download started
+            if record.seq.wrapping_sub(end_seq) < u32::MAX / 2 {
+                next_seq = end_seq;
+                break;
+            }
+            pcap_record(record, ring.data(record), &mut out);
+            next_seq = record.seq.wrapping_add(1);
+        }
+    }
+    out
+}
+
+/**
+ * Sends new records to the UDP stream: pcap header first, then one record per datagram
+*/
+pub struct Streamer {
+    sock: UdpSocket,
+    dest: Option<SocketAddrV4>,
+    next_seq: u32,
+}
+
+impl Streamer {
+    pub fn new(local_ip: std::net::Ipv4Addr) -> anyhow::Result<Self> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(local_ip, 0))?;
+        Ok(Self { sock, dest: None, next_seq: 0 })
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let dest = *STREAM.lock().unwrap();
+        if dest != self.dest {
+            self.dest = dest;
+            self.next_seq = RING.lock().unwrap().next_seq;
+            if let Some(dest) = dest {
+                self.sock.send_to(&pcap_header(), dest)?;
+            }
+        }
+        let dest = match self.dest {
+            Some(dest) => dest,
+            None => return Ok(()),
+        };
+
+        loop {
+            // Don't hold the ring while sending
+            let mut datagram = Vec::new();
+            {
+                let ring = RING.lock().unwrap();
+                let record = match ring.index_from(self.next_seq) {
+                    Some(index) => &ring.records[index],
+                    None => break,
+                };
+                self.next_seq = record.seq.wrapping_add(1);
+                pcap_record(record, ring.data(record), &mut datagram);
+            }
+            self.sock.send_to(&datagram, dest)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Block until a record is captured or the stream destination changes
+    */
+    pub fn idle(&self) {
+        let ring = RING.lock().unwrap();
+        let next_seq = self.next_seq;
+        let _ = RING_COND.wait_timeout_while(ring, CAPTURE_IDLE_TIMEOUT, |r| r.next_seq == next_seq).unwrap();
+    }
+}
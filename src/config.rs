@@ -0,0 +1,167 @@
+use anyhow::Result;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use crate::network::IP_SETTINGS_LEN;
+use crate::radio::RADIO_SETTINGS_LEN;
+
+const NAMESPACE: &str = "station";
+const PMK_KEY: &str = "pmk";
+const SIG_COUNTER_KEY: &str = "sigctr";
+const AUTH_TIMESTAMP_KEY: &str = "authts";
+const IP_SETTINGS_KEY: &str = "ip";
+const RADIO_SETTINGS_KEY: &str = "radio";
+pub const KEY_LEN: usize = 16;
+
+/**
+ * Runtime configuration persisted in NVS
+*/
+pub struct Config {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Config {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+
+    fn get_fixed<const N: usize>(&self, name: &str) -> Option<[u8; N]> {
+        let mut buf = [0u8; N];
+        match self.nvs.get_raw(name, &mut buf) {
+            Ok(Some(value)) if value.len() == N => {
+                let mut out = [0u8; N];
+                out.copy_from_slice(value);
+                Some(out)
+            }
+            _ => None,
+        }
+    }
+
+    fn get_key(&self, name: &str) -> Option<[u8; KEY_LEN]> {
+        self.get_fixed(name)
+    }
+
+    /**
+     * ESPNOW primary master key, used to encrypt the LMKs
+    */
+    pub fn pmk(&self) -> Option<[u8; KEY_LEN]> {
+        self.get_key(PMK_KEY)
+    }
+
+    pub fn set_pmk(&mut self, pmk: &[u8; KEY_LEN]) -> Result<()> {
+        self.nvs.set_raw(PMK_KEY, pmk)?;
+        Ok(())
+    }
+
+    /**
+     * Local master key of the peer. Peers with LMK are encrypted.
+    */
+    pub fn lmk(&self, device_no: usize) -> Option<[u8; KEY_LEN]> {
+        self.get_key(&format!("lmk{device_no}"))
+    }
+
+    pub fn set_lmk(&mut self, device_no: usize, lmk: &[u8; KEY_LEN]) -> Result<()> {
+        self.nvs.set_raw(&format!("lmk{device_no}"), lmk)?;
+        Ok(())
+    }
+
+    pub fn clear_lmk(&mut self, device_no: usize) -> Result<()> {
+        self.nvs.remove(&format!("lmk{device_no}"))?;
+        Ok(())
+    }
+
+    /**
+     * Frame signing key of the peer. Frames to/from peers with a signing key carry counter and HMAC.
+    */
+    pub fn sig_key(&self, device_no: usize) -> Option<[u8; KEY_LEN]> {
+        self.get_key(&format!("sig{device_no}"))
+    }
+
+    pub fn set_sig_key(&mut self, device_no: usize, key: &[u8; KEY_LEN]) -> Result<()> {
+        self.nvs.set_raw(&format!("sig{device_no}"), key)?;
+        Ok(())
+    }
+
+    pub fn clear_sig_key(&mut self, device_no: usize) -> Result<()> {
+        self.nvs.remove(&format!("sig{device_no}"))?;
+        Ok(())
+    }
+
+    /**
+     * High-water mark of the upstream frame counter of the peer
+    */
+    pub fn rx_counter(&self, device_no: usize) -> u32 {
+        self.nvs.get_u32(&format!("rxctr{device_no}")).ok().flatten().unwrap_or(0)
+    }
+
+    pub fn set_rx_counter(&mut self, device_no: usize, counter: u32) -> Result<()> {
+        self.nvs.set_u32(&format!("rxctr{device_no}"), counter)?;
+        Ok(())
+    }
+
+    /**
+     * Log level of the module set at runtime, LevelFilter as u32
+    */
+    pub fn log_level(&self, module: &str) -> Option<u32> {
+        self.nvs.get_u32(&format!("log{module}")).ok().flatten()
+    }
+
+    pub fn set_log_level(&mut self, module: &str, level: u32) -> Result<()> {
+        self.nvs.set_u32(&format!("log{module}"), level)?;
+        Ok(())
+    }
+
+    pub fn clear_log_level(&mut self, module: &str) -> Result<()> {
+        self.nvs.remove(&format!("log{module}"))?;
+        Ok(())
+    }
+
+    /**
+     * Ethernet IP settings, see network.rs. Applied at boot.
+    */
+    pub fn ip_settings(&self) -> Option<[u8; IP_SETTINGS_LEN]> {
+        self.get_fixed(IP_SETTINGS_KEY)
+    }
+
+    pub fn set_ip_settings(&mut self, settings: &[u8; IP_SETTINGS_LEN]) -> Result<()> {
+        self.nvs.set_raw(IP_SETTINGS_KEY, settings)?;
+        Ok(())
+    }
+
+    /**
+     * WiFi AP / ESP-NOW radio settings, see radio.rs
+    */
+    pub fn radio_settings(&self) -> Option<[u8; RADIO_SETTINGS_LEN]> {
+        self.get_fixed(RADIO_SETTINGS_KEY)
+    }
+
+    pub fn set_radio_settings(&mut self, settings: &[u8; RADIO_SETTINGS_LEN]) -> Result<()> {
+        self.nvs.set_raw(RADIO_SETTINGS_KEY, settings)?;
+        Ok(())
+    }
+
+    /**
+     * High-water mark of the downstream frame counter
+    */
+    pub fn sig_counter(&self) -> u32 {
+        self.nvs.get_u32(SIG_COUNTER_KEY).ok().flatten().unwrap_or(0)
+    }
+
+    pub fn set_sig_counter(&mut self, counter: u32) -> Result<()> {
+        self.nvs.set_u32(SIG_COUNTER_KEY, counter)?;
+        Ok(())
+    }
+
+    /**
+     * Timestamp reserved for signed OSC commands, every accepted one is below it
+    */
+    pub fn auth_timestamp(&self) -> Option<i64> {
+        self.get_fixed(AUTH_TIMESTAMP_KEY).map(i64::from_be_bytes)
+    }
+
+    pub fn set_auth_timestamp(&mut self, timestamp: i64) -> Result<()> {
+        self.nvs.set_raw(AUTH_TIMESTAMP_KEY, &timestamp.to_be_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{bail, Result};
+use log::*;
+
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::mdns::EspMdns;
+
+use crate::bus::{self, ConfigChange, Event, Overflow, Subscriber};
+use crate::web::HTTP_PORT;
+
+// mDNS query for .local destinations
+const MDNS_QUERY_TIMEOUT_MS: u32 = 2000;
+// Destination hostname is resolved again at this interval, unless DEST_RESOLVE_INTERVAL_MS is set
+pub const DEST_RESOLVE_INTERVAL_DEFAULT: Duration = Duration::from_secs(30);
+// Retry interval while the destination never resolved since boot
+const DEST_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const RESOLVER_EVENT_CAPACITY: usize = 4;
+const RESOLVER_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ * mDNS responder advertising the OSC service and the HTTP server when enabled, keep it alive to stay advertised
+ * TXT records: id (station id), channel (ESP-NOW channel), version (firmware version)
+*/
+pub struct Discovery {
+    _mdns: EspMdns,
+}
+
+impl Discovery {
+    pub fn start(hostname: &str, instance: &str, osc_port: u16, station_id: &str, channel: u8, http: bool) -> Result<Self> {
+        let mut mdns = EspMdns::take()?;
+        mdns.set_hostname(hostname)?;
+        mdns.set_instance_name(instance)?;
+
+        let channel = channel.to_string();
+        let txt = [
+            ("id", station_id),
+            ("channel", channel.as_str()),
+            ("version", env!("CARGO_PKG_VERSION")),
+        ];
+        mdns.add_service(None, "_osc", "_udp", osc_port, &txt)?;
+        if http {
+            mdns.add_service(None, "_http", "_tcp", HTTP_PORT, &[("path", "/capture.pcap")])?;
+        }
+
+        info!("mDNS: {hostname}.local, \"{instance}\" _osc._udp port {osc_port}, http:{http}");
+        Ok(Self { _mdns: mdns })
+    }
+}
+
+/**
+ * IPv4 address, .local hostname resolved over mDNS, or hostname resolved over DNS
+*/
+pub fn resolve(host: &str) -> Result<Ipv4Addr> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Ok(ip);
+    }
+    match host.strip_suffix(".local") {
+        Some(name) => resolve_mdns(name),
+        None => resolve_dns(host),
+    }
+}
+
+fn resolve_dns(host: &str) -> Result<Ipv4Addr> {
+    let ip = (host, 0).to_socket_addrs()?.find_map(|addr| match addr.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    });
+    match ip {
+        Some(ip) => {
+            info!("Resolved {host}: {ip}");
+            Ok(ip)
+        }
+        None => bail!("{host} has no IPv4 address"),
+    }
+}
+
+fn resolve_mdns(name: &str) -> Result<Ipv4Addr> {
+    let c_name = CString::new(name)?;
+    let mut addr = esp_idf_sys::esp_ip4_addr_t::default();
+    let err = unsafe { esp_idf_sys::mdns_query_a(c_name.as_ptr(), MDNS_QUERY_TIMEOUT_MS, &mut addr) };
+    if err != esp_idf_sys::ESP_OK {
+        bail!("{name}.local not resolved: {err}");
+    }
+    // Network byte order
+    let ip = Ipv4Addr::from(addr.addr.to_le_bytes());
+    info!("Resolved {name}.local: {ip}");
+    Ok(ip)
+}
+
+/**
+ * Keeps the OSC destination resolved: on /setdestip, and periodically for hostnames so a destination
+ * changing address (DHCP) is followed. Changes are published as ConfigChange::DestIp.
+*/
+pub struct Resolver {
+    events: Subscriber,
+    host: String,
+    ip: Ipv4Addr,
+    interval: Duration,
+    last_resolve: Instant,
+}
+
+impl Resolver {
+    pub fn subscribe() -> Subscriber {
+        bus::subscribe("resolve", RESOLVER_EVENT_CAPACITY, Overflow::DropOldest, |e| matches!(e, Event::ConfigChange(ConfigChange::DestHost(_))))
+    }
+
+    /**
+     * host as set at build time, ip as resolved at boot
+    */
+    pub fn new(host: &str, ip: Ipv4Addr, interval: Duration, events: Subscriber) -> Self {
+        Self { events, host: host.to_string(), ip, interval, last_resolve: Instant::now() }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        while let Some(event) = self.events.try_recv() {
+            if let Event::ConfigChange(ConfigChange::DestHost(host)) = event {
+                self.host = host;
+                // Reported back as /destip, even when the address is the same
+                self.resolve(true)?;
+            }
+        }
+        let interval = if self.ip.is_unspecified() { self.interval.min(DEST_RETRY_INTERVAL) } else { self.interval };
+        if self.last_resolve.elapsed() >= interval && self.host.parse::<Ipv4Addr>().is_err() {
+            self.resolve(false)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * The previous address is kept when the host doesn't resolve
+    */
+    fn resolve(&mut self, announce: bool) -> Result<()> {
+        self.last_resolve = Instant::now();
+        let ip = resolve(&self.host)?;
+        if announce || ip != self.ip {
+            info!("Destination {} at {ip}", self.host);
+            self.ip = ip;
+            bus::publish(Event::ConfigChange(ConfigChange::DestIp(ip)));
+        }
+        Ok(())
+    }
+
+    /**
+     * Block until /setdestip, or the next check of the interval
+    */
+    pub fn idle(&self) {
+        self.events.wait(RESOLVER_IDLE_TIMEOUT);
+    }
+}
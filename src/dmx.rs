@@ -0,0 +1,377 @@
+use anyhow::Result;
+use log::*;
+
+use std::net::{SocketAddrV4, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use crate::osc::Msg;
+use crate::bus::{self, Event, Priority};
+use crate::poll;
+#[cfg(feature = "artnet")]
+use crate::espnow::{NODE_ADDRESSES, NODE_STATUS, NODE_STATUS_OK, NODE_STATUS_FAILED};
+#[cfg(feature = "artnet")]
+use std::sync::atomic::Ordering;
+
+// Longest wait for a DMX packet while no value is pending
+const DMX_RECV_TIMEOUT: Duration = Duration::from_millis(100);
+// Bridge sleeps after an error, so it doesn't spin
+const DMX_ERROR_INTERVAL_MS: Duration = Duration::from_millis(10);
+// Minimum interval between frames to the same node, keeps 2.4GHz link from saturating
+const NODE_MIN_INTERVAL_MS: Duration = Duration::from_millis(25);
+
+#[cfg(feature = "artnet")]
+pub const ARTNET_PORT: u16 = 6454;
+#[cfg(feature = "artnet")]
+const ARTPOLLREPLY_LEN: usize = 239;
+#[cfg(feature = "sacn")]
+pub const SACN_PORT: u16 = 5568;
+
+// Max channels per mapping, fits the 32 byte ESPNOW frame with header, device no, v2 header and signature
+const DMX_MAX_WIDTH: usize = 8;
+
+pub struct DmxMapping {
+    // Art-Net port address (net, sub-net, universe) or sACN universe
+    pub universe: u16,
+    // First DMX channel, 1-512
+    pub channel: u16,
+    // Number of channels sent to the node
+    pub width: usize,
+    pub device_no: u8,
+}
+
+// DMX to ESP-NOW mapping. Changed values are sent as Msg::Dmx: [0x64, Device No, values...]
+pub const DMX_MAP: [DmxMapping; 2] = [
+    DmxMapping { universe: 1, channel: 1, width: 3, device_no: 1 },
+    DmxMapping { universe: 1, channel: 4, width: 3, device_no: 2 },
+];
+
+#[derive(Default, Clone, Copy)]
+struct MappingState {
+    last_sent: Option<[u8; DMX_MAX_WIDTH]>,
+    pending: Option<[u8; DMX_MAX_WIDTH]>,
+}
+
+/**
+ * Art-Net / sACN (E1.31) receiver, maps DMX channel ranges to ESP-NOW nodes
+*/
+pub struct DmxBridge {
+    #[cfg(feature = "artnet")]
+    artnet_sock: UdpSocket,
+    #[cfg(feature = "artnet")]
+    ip: embedded_svc::ipv4::Ipv4Addr,
+    #[cfg(feature = "artnet")]
+    mac: [u8; 6],
+    #[cfg(feature = "artnet")]
+    poll_count: u16,
+    #[cfg(feature = "sacn")]
+    sacn_sock: UdpSocket,
+    buf: [u8; 640],
+    states: [MappingState; DMX_MAP.len()],
+    node_last_send: Vec<(u8, Instant)>,
+}
+
+impl DmxBridge {
+    pub fn new(
+        ip: embedded_svc::ipv4::Ipv4Addr,
+        #[cfg(feature = "artnet")] mac: [u8; 6],
+    ) -> Self {
+        #[cfg(feature = "artnet")]
+        let artnet_sock = {
+            let addr = SocketAddrV4::new(ip, ARTNET_PORT);
+            let sock = UdpSocket::bind(addr).unwrap();
+            sock.set_nonblocking(true).unwrap();
+            info!("Art-Net listening to {addr}");
+            sock
+        };
+
+        #[cfg(feature = "sacn")]
+        let sacn_sock = {
+            // Multicast is only delivered to unspecified address
+            let addr = SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED, SACN_PORT);
+            let sock = UdpSocket::bind(addr).unwrap();
+            sock.set_nonblocking(true).unwrap();
+            for mapping in DMX_MAP.iter() {
+                let [hi, lo] = mapping.universe.to_be_bytes();
+                let group = std::net::Ipv4Addr::new(239, 255, hi, lo);
+                if let Err(e) = sock.join_multicast_v4(&group, &ip) {
+                    error!("sACN: failed to join {group}: {e}");
+                }
+            }
+            info!("sACN listening to {addr}");
+            sock
+        };
+
+        Self {
+            #[cfg(feature = "artnet")]
+            artnet_sock,
+            #[cfg(feature = "artnet")]
+            ip,
+            #[cfg(feature = "artnet")]
+            mac,
+            #[cfg(feature = "artnet")]
+            poll_count: 0,
+            #[cfg(feature = "sacn")]
+            sacn_sock,
+            buf: [0u8; 640],
+            states: [MappingState::default(); DMX_MAP.len()],
+            node_last_send: vec![],
+        }
+    }
+
+    /**
+     * Receive DMX packets and send changed values to nodes.
+     * Waits in poll on the Art-Net and sACN sockets until a packet arrives or a pending value is due.
+    */
+    pub fn run(&mut self) -> Result<()> {
+        let timeout = self.recv_timeout();
+
+        #[cfg(all(feature = "artnet", feature = "sacn"))]
+        let [artnet_ready, sacn_ready] = poll::wait_readable([self.artnet_sock.as_raw_fd(), self.sacn_sock.as_raw_fd()], timeout)?;
+        #[cfg(all(feature = "artnet", not(feature = "sacn")))]
+        let [artnet_ready] = poll::wait_readable([self.artnet_sock.as_raw_fd()], timeout)?;
+        #[cfg(all(feature = "sacn", not(feature = "artnet")))]
+        let [sacn_ready] = poll::wait_readable([self.sacn_sock.as_raw_fd()], timeout)?;
+
+        #[cfg(feature = "artnet")]
+        if artnet_ready {
+            while let Ok((size, addr)) = self.artnet_sock.recv_from(&mut self.buf) {
+                if let Some((universe, start, len)) = parse_artdmx(&self.buf[..size]) {
+                    self.update_universe(universe, start, len);
+                }
+                else if is_artpoll(&self.buf[..size]) {
+                    self.reply_poll(addr.ip())?;
+                }
+            }
+        }
+
+        #[cfg(feature = "sacn")]
+        if sacn_ready {
+            while let Ok((size, _addr)) = self.sacn_sock.recv_from(&mut self.buf) {
+                if let Some((universe, start, len)) = parse_sacn(&self.buf[..size]) {
+                    self.update_universe(universe, start, len);
+                }
+            }
+        }
+
+        self.flush();
+        Ok(())
+    }
+
+    /**
+     * Time until the first pending value may be sent, bounded by the receive timeout
+    */
+    fn recv_timeout(&self) -> Duration {
+        let now = Instant::now();
+        let mut timeout = DMX_RECV_TIMEOUT;
+        for (mapping, state) in DMX_MAP.iter().zip(self.states.iter()) {
+            if state.pending.is_none() {
+                continue;
+            }
+            let wait = match self.node_last_send.iter().find(|(no, _)| *no == mapping.device_no) {
+                Some((_, last)) => NODE_MIN_INTERVAL_MS.saturating_sub(now.duration_since(*last)),
+                None => Duration::ZERO,
+            };
+            timeout = timeout.min(wait);
+        }
+        timeout
+    }
+
+    /**
+     * Compare mapped channel ranges against last sent values, mark changes as pending
+    */
+    fn update_universe(&mut self, universe: u16, start: usize, len: usize) {
+        let dmx = &self.buf[start..start + len];
+        for (mapping, state) in DMX_MAP.iter().zip(self.states.iter_mut()) {
+            if mapping.universe != universe {
+                continue;
+            }
+            let mut values = [0u8; DMX_MAX_WIDTH];
+            let first = mapping.channel.max(1) as usize - 1;
+            for (i, v) in values.iter_mut().take(mapping.width.min(DMX_MAX_WIDTH)).enumerate() {
+                *v = dmx.get(first + i).copied().unwrap_or(0);
+            }
+            if state.last_sent != Some(values) {
+                state.pending = Some(values);
+            }
+            else {
+                state.pending = None;
+            }
+        }
+    }
+
+    /**
+     * Send pending values to nodes whose rate limit interval has passed.
+     * Values arriving within the interval are kept, so the latest value always reaches the node.
+    */
+    fn flush(&mut self) {
+        let now = Instant::now();
+        for i in 0..DMX_MAP.len() {
+            let values = match self.states[i].pending {
+                Some(values) => values,
+                None => continue,
+            };
+            let mapping = &DMX_MAP[i];
+
+            let node = self.node_last_send.iter_mut().find(|(no, _)| *no == mapping.device_no);
+            if let Some((_, last)) = &node {
+                if now.duration_since(*last) < NODE_MIN_INTERVAL_MS {
+                    continue;
+                }
+            }
+
+            let width = mapping.width.min(DMX_MAX_WIDTH);
+            let mut msg_buf = vec![Msg::Dmx as u8, mapping.device_no];
+            msg_buf.extend_from_slice(&values[..width]);
+
+            if !bus::publish(Event::Downstream(msg_buf, Priority::Normal)) {
+                // Keep it pending, try again next round
+                continue;
+            }
+
+            match node {
+                Some((_, last)) => *last = now,
+                None => self.node_last_send.push((mapping.device_no, now)),
+            }
+            self.states[i].last_sent = Some(values);
+            self.states[i].pending = None;
+        }
+    }
+
+    /**
+     * Answer ArtPoll with the station itself, then each ESP-NOW node as a virtual node.
+     * Bind index tells the console which replies belong to this station.
+    */
+    #[cfg(feature = "artnet")]
+    fn reply_poll(&mut self, poller: std::net::IpAddr) -> Result<()> {
+        self.poll_count = self.poll_count.wrapping_add(1) % 10000;
+        let dest = std::net::SocketAddr::new(poller, ARTNET_PORT);
+
+        let universes: Vec<u16> = DMX_MAP.iter().map(|m| m.universe).collect();
+        let report = format!("#0001 [{:04}] {} ESP-NOW nodes", self.poll_count, NODE_ADDRESSES.len() - 1);
+        let reply = artpoll_reply(self.ip, self.mac, 1, "ESPNOW Station", "ESPNOW OSC Station", &report, &universes);
+        self.artnet_sock.send_to(&reply, dest)?;
+
+        // Broadcast address is not a node
+        for (no, mac) in NODE_ADDRESSES.iter().enumerate().skip(1) {
+            let status = match NODE_STATUS[no].load(Ordering::Relaxed) {
+                NODE_STATUS_OK => "ok",
+                NODE_STATUS_FAILED => "failed",
+                _ => "unknown",
+            };
+            let universes: Vec<u16> = DMX_MAP.iter()
+                .filter(|m| m.device_no as usize == no)
+                .map(|m| m.universe)
+                .collect();
+            let short_name = format!("ESPNOW {no}");
+            let long_name = format!("ESP-NOW node {no} {:02X?} {status}", mac);
+            let report = format!("#0001 [{:04}] {status}", self.poll_count);
+            let reply = artpoll_reply(self.ip, *mac, no as u8 + 1, &short_name, &long_name, &report, &universes);
+            self.artnet_sock.send_to(&reply, dest)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * run waits in poll, only sleep after an error
+    */
+    pub fn idle(&self) {
+        std::thread::sleep(DMX_ERROR_INTERVAL_MS);
+    }
+}
+
+/**
+ * ArtDmx packet: returns universe (port address), start and length of DMX data
+*/
+#[cfg(feature = "artnet")]
+fn parse_artdmx(packet: &[u8]) -> Option<(u16, usize, usize)> {
+    if packet.len() < 18 || &packet[..8] != b"Art-Net\0" {
+        return None;
+    }
+    // OpDmx, little endian
+    if packet[8] != 0x00 || packet[9] != 0x50 {
+        return None;
+    }
+    let universe = ((packet[15] as u16 & 0x7F) << 8) | packet[14] as u16;
+    let len = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    Some((universe, 18, len.min(packet.len() - 18)))
+}
+
+#[cfg(feature = "artnet")]
+fn is_artpoll(packet: &[u8]) -> bool {
+    // OpPoll, little endian
+    packet.len() >= 12 && &packet[..8] == b"Art-Net\0" && packet[8] == 0x00 && packet[9] == 0x20
+}
+
+/**
+ * ArtPollReply with up to 4 output ports. All ports share net and sub-net of the first universe.
+*/
+#[cfg(feature = "artnet")]
+fn artpoll_reply(ip: embedded_svc::ipv4::Ipv4Addr, mac: [u8; 6], bind_index: u8,
+    short_name: &str, long_name: &str, report: &str, universes: &[u16]) -> [u8; ARTPOLLREPLY_LEN] {
+    let mut reply = [0u8; ARTPOLLREPLY_LEN];
+    reply[..8].copy_from_slice(b"Art-Net\0");
+    // OpPollReply
+    reply[8..10].copy_from_slice(&0x2100u16.to_le_bytes());
+    reply[10..14].copy_from_slice(&ip.octets());
+    reply[14..16].copy_from_slice(&ARTNET_PORT.to_le_bytes());
+
+    let copy_str = |dst: &mut [u8], s: &str| {
+        // Keep null terminator
+        let len = s.len().min(dst.len() - 1);
+        dst[..len].copy_from_slice(&s.as_bytes()[..len]);
+    };
+    copy_str(&mut reply[26..44], short_name);
+    copy_str(&mut reply[44..108], long_name);
+    copy_str(&mut reply[108..172], report);
+
+    let net_sub = universes.first().copied().unwrap_or(0);
+    reply[18] = ((net_sub >> 8) & 0x7F) as u8;
+    reply[19] = ((net_sub >> 4) & 0x0F) as u8;
+    // Indicators normal, port address set by front panel
+    reply[23] = 0xD0;
+
+    let mut ports = 0;
+    for universe in universes.iter().filter(|u| *u >> 4 == net_sub >> 4).take(4) {
+        // DMX512 output, data transmitted
+        reply[174 + ports] = 0x80;
+        reply[182 + ports] = 0x80;
+        reply[190 + ports] = (*universe & 0x0F) as u8;
+        ports += 1;
+    }
+    reply[173] = ports as u8;
+
+    // StNode
+    reply[200] = 0x00;
+    reply[201..207].copy_from_slice(&mac);
+    reply[207..211].copy_from_slice(&ip.octets());
+    reply[211] = bind_index;
+    // Supports 15 bit port address
+    reply[212] = 0x08;
+    reply
+}
+
+/**
+ * E1.31 data packet: returns universe, start and length of DMX data (without start code)
+*/
+#[cfg(feature = "sacn")]
+fn parse_sacn(packet: &[u8]) -> Option<(u16, usize, usize)> {
+    if packet.len() < 126 || &packet[4..16] != b"ASC-E1.17\0\0\0" {
+        return None;
+    }
+    // Root vector VECTOR_ROOT_E131_DATA, framing vector VECTOR_E131_DATA_PACKET
+    if packet[18..22] != [0, 0, 0, 4] || packet[40..44] != [0, 0, 0, 2] {
+        return None;
+    }
+    // Ignore preview data
+    if packet[112] & 0x80 != 0 {
+        return None;
+    }
+    // Only null start code carries dimmer data
+    if packet[125] != 0 {
+        return None;
+    }
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    Some((universe, 126, count.saturating_sub(1).min(packet.len() - 126)))
+}
@@ -1,6 +1,7 @@
 use anyhow::{bail, Result};
-use std::time::Duration;
-use bbqueue::framed::{FrameConsumer, FrameProducer};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use log::*;
 
 extern crate num;
@@ -9,120 +10,426 @@ extern crate num_derive;
 use esp_idf_sys::{self as _, esp_interface_t_ESP_IF_WIFI_AP}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_svc::espnow::*;
 
-use crate::{PRODUCER_UPSTREAM, MSG_BUF_DOWNSTREAM, MSG_BUF_LED, PRODUCER_SENDERROR, MSG_BUF_ESPNOWRETRY, ESPNOW_RETRY_COUNT, PRODUCER_ESPNOWRETRY, ESPNOW_MAX_RETRY, ESPNOW_LAST_PACKET, ESPNOW_LAST_PACKET_LENGTH};
+use crate::protocol::{self, SeqCounter, ESPNOW_FRAME_LEN};
+use crate::config::{Config, KEY_LEN};
+use crate::signature::{self, TxCounter, SPOOF_COUNT};
+use crate::scheduler::Scheduler;
+use crate::estop::Estop;
+use crate::liveness::{self, Liveness};
+use crate::osc::Msg;
+use crate::bus::{self, CallbackSink, Event, SendResult, ConfigChange, Indicator, Overflow, Priority, Subscriber, DOWNSTREAM_LATENCY};
+use crate::stats::{self, ESPNOW_SENT, ESPNOW_DELIVERED, ESPNOW_FAILED, ESPNOW_RETRIED};
+use crate::capture::{self, Direction, Status};
+use crate::radio;
+use espnow_callback::{RecvContext, SendContext, Sink};
+use crate::ESPNOW_MAX_RETRY;
 
-const ESPNOW_FRAME_INTERVAL_MS: Duration = Duration::from_millis(1);
+// Queued events for the ESPNOW thread
+const ESPNOW_EVENT_CAPACITY: usize = 32;
+// Downstream publishers wait for room rather than losing a command
+const ESPNOW_BLOCK_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Longest wait when nothing is due, bounds the liveness check and saving the upstream counters
+const ESPNOW_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+// Time to wait for app level ACK before resending
+const APP_ACK_TIMEOUT_US: u32 = 100_000;
 
 // Adding device's MAC address to NODE_ADDRESSES const
 const DEV1_MAC: [u8;6] = [0x50, 0x02, 0x91, 0x9F, 0xCF, 0x9C];
 const DEV2_MAC: [u8;6] = [0x50, 0x02, 0x91, 0x87, 0x95, 0x81];
-const NODE_ADDRESSES: [[u8;6]; 3] = [BROADCAST, DEV1_MAC, DEV2_MAC];
+pub const NODE_ADDRESSES: [[u8;6]; 3] = [BROADCAST, DEV1_MAC, DEV2_MAC];
+
+// Last known state of each node, updated from the ESPNOW callbacks
+pub const NODE_STATUS_UNKNOWN: u8 = 0;
+pub const NODE_STATUS_OK: u8 = 1;
+pub const NODE_STATUS_FAILED: u8 = 2;
+#[allow(clippy::declare_interior_mutable_const)]
+const NODE_STATUS_INIT: AtomicU8 = AtomicU8::new(NODE_STATUS_UNKNOWN);
+pub static NODE_STATUS: [AtomicU8; NODE_ADDRESSES.len()] = [NODE_STATUS_INIT; NODE_ADDRESSES.len()];
+
+/**
+ * Device no of the MAC address, 0 when not in the node list
+*/
+pub fn device_no(mac_addr: &[u8]) -> u8 {
+    for (i, n) in NODE_ADDRESSES.iter().enumerate(){
+        if n == mac_addr{
+            return i as u8;
+        }
+    }
+    0
+}
 
 pub struct Espnow{
-    receiver: FrameConsumer<'static, MSG_BUF_DOWNSTREAM>,
-    led_producer: FrameProducer<'static, MSG_BUF_LED>,
-    espnow_retry_cosumer: FrameConsumer<'static, MSG_BUF_ESPNOWRETRY>,
+    events: Subscriber,
+    scheduler: Scheduler,
+    estop: Estop,
+    liveness: Liveness,
+    seq_counter: SeqCounter,
+    tx_counter: TxCounter,
+    // Last frame sent to each node, resent as is by retries, and app level ACK retries of each node
+    last_frames: [([u8; ESPNOW_FRAME_LEN], usize); NODE_ADDRESSES.len()],
+    // Priority of the last frame, high priority retries are sent first
+    last_priorities: [Priority; NODE_ADDRESSES.len()],
+    ack_retries: [usize; NODE_ADDRESSES.len()],
+    config: Arc<Mutex<Config>>,
+    peer_channel: u8,
+    // Shared with the send callback, liveness probes are marked in it
+    send_context: Arc<SendContext<CallbackSink>>,
     espnow: EspNow,
 }
 
 impl Espnow{
-    pub fn new(receiver: FrameConsumer<'static, MSG_BUF_DOWNSTREAM>, led_producer: FrameProducer<'static, MSG_BUF_LED>,
-        espnow_retry_cosumer: FrameConsumer<'static, MSG_BUF_ESPNOWRETRY>) -> Self {
+    /**
+     * Downstream frames from every source (OSC, MIDI, Art-Net...), send retries, peer key changes,
+     * e-stop commands, and node boot / e-stop ACK replies
+    */
+    pub fn subscribe() -> Subscriber {
+        bus::subscribe("espnow", ESPNOW_EVENT_CAPACITY, Overflow::Block(ESPNOW_BLOCK_TIMEOUT), |e| matches!(e,
+            Event::Downstream(..)
+            | Event::SendResult { result: SendResult::Retry, .. }
+            | Event::ConfigChange(ConfigChange::PeerKeys(_))
+            | Event::Estop(_))
+            || matches!(e, Event::Reply(frame) if frame[0] == Msg::Boot as u8 || frame[0] == Msg::EstopAck as u8))
+    }
+
+    pub fn new(events: Subscriber, tx_counter: TxCounter, liveness: Liveness, config: Arc<Mutex<Config>>) -> Self {
         let espnow = EspNow::take().unwrap();
-        let _ = espnow.register_recv_cb(recv_callback).unwrap();
-        let _ = espnow.register_send_cb(send_callback).unwrap();
+        // Callbacks run in the WiFi task, they only publish events
+        let recv_context = RecvContext::new(CallbackSink);
+        let _ = espnow.register_recv_cb(move |mac_addr: &[u8], data: &[u8]| on_recv(&recv_context, mac_addr, data)).unwrap();
+        let send_context = Arc::new(SendContext::new(CallbackSink, NODE_ADDRESSES.len(), ESPNOW_MAX_RETRY));
+        let callback_context = send_context.clone();
+        let _ = espnow.register_send_cb(move |mac_addr: &[u8], status: SendStatus| on_send(&callback_context, mac_addr, status)).unwrap();
         Self {
-            receiver,
-            led_producer,
-            espnow_retry_cosumer,
+            events,
+            scheduler: Scheduler::new(NODE_ADDRESSES.len()),
+            estop: Estop::new(),
+            liveness,
+            seq_counter: SeqCounter::new(),
+            tx_counter,
+            last_frames: [([0u8; ESPNOW_FRAME_LEN], 0); NODE_ADDRESSES.len()],
+            last_priorities: [Priority::Normal; NODE_ADDRESSES.len()],
+            ack_retries: [0; NODE_ADDRESSES.len()],
+            config,
+            peer_channel: 0,
+            send_context,
             espnow,
         }
     }
 
     /**
      * Adding peer addresses to peer list
+     * Peers with LMK in the config are encrypted. Broadcast peer can't be encrypted.
     */
     pub fn config(&mut self, peer_channel: u8){
+        self.peer_channel = peer_channel;
+        let config = self.config.lock().unwrap();
 
-        for peer_addr in NODE_ADDRESSES{
-            let peer_info = PeerInfo {
-                peer_addr: peer_addr,
-                lmk: [0u8; 16],
-                channel: peer_channel,
-                encrypt: false,
-                ifidx: esp_interface_t_ESP_IF_WIFI_AP,
-                priv_: std::ptr::null_mut(),
-            };
-            if let Err(e) = self.espnow.add_peer(peer_info){
+        if let Some(pmk) = config.pmk() {
+            if let Err(e) = self.espnow.set_pmk(&pmk) {
+                error!("ESPNOW set pmk error: {e}");
+            }
+        }
+
+        for (no, peer_addr) in NODE_ADDRESSES.iter().enumerate(){
+            let lmk = if no == 0 { None } else { config.lmk(no) };
+            signature::load_key(no, &config);
+            capture::redact(no, lmk.is_some());
+            if let Err(e) = self.espnow.add_peer(self.peer_info(*peer_addr, lmk)){
                 error!("ESPNOW add peer error: {e}");
             };
         };
+
+        // Peers on another channel than the AP can't be reached
+        if peer_channel != 0 {
+            match radio::channel() {
+                Ok(channel) if channel != peer_channel => error!("ESPNOW peer channel {peer_channel} doesn't match WiFi channel {channel}"),
+                Ok(_) => {}
+                Err(e) => error!("WiFi channel error: {e}"),
+            }
+        }
+    }
+
+    fn peer_info(&self, peer_addr: [u8; 6], lmk: Option<[u8; KEY_LEN]>) -> PeerInfo {
+        PeerInfo {
+            peer_addr: peer_addr,
+            lmk: lmk.unwrap_or([0u8; KEY_LEN]),
+            channel: self.peer_channel,
+            encrypt: lmk.is_some(),
+            ifidx: esp_interface_t_ESP_IF_WIFI_AP,
+            priv_: std::ptr::null_mut(),
+        }
     }
 
     /**
-     * On receiving OSC packet, send out ESPnow.
+     * Apply key changes from OSC. Device no 0 means PMK changed, all peers are added again.
     */
-    pub fn run(&mut self) -> Result<()> {
-        if let Some(frame) = self.receiver.read() {
-            info!("downstream msg received");
-
-            let mut data = [0u8; 10];
-            data[..frame.len()].copy_from_slice(&frame);
-            let target_no = data[1] as usize;
-
-            if NODE_ADDRESSES.len() > target_no {
-                let ret = self.espnow.send(NODE_ADDRESSES[target_no], &data[..frame.len()]);
-                match ret {
-                    Ok(_) => {
-                        // Send out led indication
-                        if let Ok(mut wg) = self.led_producer.grant(1){
-                            wg.to_commit(1);
-                            wg[0] = 1;
-                            wg.commit(1);
-                        }
-                        unsafe{
-                            if frame.len() < 10 {
-                                ESPNOW_LAST_PACKET[..frame.len()].copy_from_slice(&data[..frame.len()]);
-                                ESPNOW_LAST_PACKET_LENGTH = frame.len();
-                            }
-                        }
+    fn apply_peer_config(&mut self, target_no: usize) -> Result<()> {
+        if target_no == 0 {
+            info!("ESPNOW: reload all peers");
+            // Peers that failed to delete are still added again, so no node is left without a peer
+            for peer_addr in NODE_ADDRESSES.iter() {
+                if let Err(e) = self.espnow.del_peer(*peer_addr) {
+                    error!("ESPNOW del peer error: {e}");
+                }
+            }
+            self.config(self.peer_channel);
+        }
+        else if NODE_ADDRESSES.len() > target_no {
+            let mut config = self.config.lock().unwrap();
+            let lmk = config.lmk(target_no);
+            signature::reload_key(target_no, &mut config)?;
+            capture::redact(target_no, lmk.is_some());
+            info!("ESPNOW: peer {target_no} encrypted:{} signed:{}", lmk.is_some(), config.sig_key(target_no).is_some());
+            drop(config);
+            self.espnow.mod_peer(self.peer_info(NODE_ADDRESSES[target_no], lmk))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Handle queued events: schedule downstream frames, collect failed frames, apply key changes.
+     * Then send in order: high priority retries, high priority frames, other retries, scheduled frames that are due.
+     * Errors are logged per event, so one failure doesn't drop the rest.
+    */
+    pub fn run(&mut self) {
+        let mut retries = Vec::new();
+        while let Some(event) = self.events.try_recv() {
+            match event {
+                Event::Downstream(frame, priority) => {
+                    info!("downstream msg received");
+                    if let Err(e) = self.scheduler.push(frame, priority) {
+                        error!("ESPNOW: {e}");
+                    }
+                }
+                Event::SendResult { device_no, result: SendResult::Retry } => {
+                    retries.push(device_no as usize);
+                }
+                Event::ConfigChange(ConfigChange::PeerKeys(device_no)) => {
+                    if let Err(e) = self.apply_peer_config(device_no as usize) {
+                        error!("ESPNOW: peer {device_no} config error: {e}");
                     }
-                    Err(e) => {
-                    bail!("Error sending out espnow msg: {e}");
+                }
+                Event::Estop(latched) => {
+                    // Commands queued or waiting for a retry before the stop must not reach the nodes after it
+                    if latched {
+                        self.scheduler.clear();
+                        self.clear_retries();
+                        retries.clear();
+                    }
+                    for frame in self.estop.command(latched, Instant::now()) {
+                        self.push_urgent(frame);
                     }
                 }
+                Event::Reply(frame) => self.handle_reply(&frame),
+                _ => {}
             }
-            else {
-                error!("This device does not exists! {target_no}");
+        }
+        self.check_estop();
+        self.check_liveness();
+        if let Err(e) = signature::save_rx_counters(&self.config) {
+            error!("ESPNOW: failed to save upstream counters: {e}");
+        }
+
+        let (urgent_retries, retries): (Vec<usize>, Vec<usize>) = retries.into_iter()
+            .partition(|no| self.last_priorities.get(*no) == Some(&Priority::High));
+        for no in urgent_retries {
+            log_send_error(self.send_retry(no));
+        }
+        while let Some(frame) = self.scheduler.pop_urgent(Instant::now()) {
+            log_send_error(self.send_downstream(&frame, Priority::High));
+        }
+        for no in retries {
+            log_send_error(self.send_retry(no));
+        }
+        while let Some(frame) = self.scheduler.pop(Instant::now()) {
+            log_send_error(self.send_downstream(&frame, Priority::Normal));
+        }
+    }
+
+    /**
+     * Forget the frames waiting for a MAC or app level ACK retry
+    */
+    fn clear_retries(&mut self) {
+        for no in 0..NODE_ADDRESSES.len() {
+            protocol::clear_outstanding(no);
+            self.ack_retries[no] = 0;
+            self.last_frames[no].1 = 0;
+            self.last_priorities[no] = Priority::Normal;
+        }
+    }
+
+    fn push_urgent(&mut self, frame: Vec<u8>) {
+        if let Err(e) = self.scheduler.push(frame, Priority::High) {
+            error!("ESPNOW: {e}");
+        }
+    }
+
+    /**
+     * Node replies: boot re-sends a latched e-stop, e-stop ACK [EstopAck, device no, latched] is reported once
+    */
+    fn handle_reply(&mut self, frame: &[u8]) {
+        let device_no = frame[1] as usize;
+        match num::FromPrimitive::from_u8(frame[0]) {
+            Some(Msg::Boot) => {
+                if let Some(frame) = self.estop.boot(device_no) {
+                    self.push_urgent(frame);
+                }
+            }
+            Some(Msg::EstopAck) => {
+                let latched = frame.get(2).copied().unwrap_or(0) != 0;
+                if self.estop.ack(device_no, latched) {
+                    bus::publish(Event::EstopAck(device_no as u8));
+                }
             }
+            _ => {}
+        }
+    }
 
-            frame.release();
-            // frame.auto_release(true);
+    /**
+     * Resend e-stop state to nodes that haven't acknowledged, report them after the deadline
+    */
+    fn check_estop(&mut self) {
+        let (frames, missing) = self.estop.check(Instant::now());
+        for frame in frames {
+            self.push_urgent(frame);
+        }
+        if let Some(missing) = missing {
+            bus::publish(Event::EstopMissing(missing));
         }
-        Ok(())
     }
 
-    
     /**
-     * When ESPNOW send is failed, retry.
+     * Probe quiet nodes, report online / offline transitions.
+     * Nodes waiting for an app level ACK are skipped, the ACK or its failure tells about them.
     */
-    pub fn send_retry(&mut self) -> Result<()> {
-        if let Some(frame) = self.espnow_retry_cosumer.read() {
-            // info!("ESPNOW: retry");
+    fn check_liveness(&mut self) {
+        let (probes, transitions) = self.liveness.check(Instant::now());
+        for no in probes {
+            if protocol::outstanding(no as usize).is_none() {
+                log_send_error(self.send_probe(no as usize));
+            }
+        }
+        for (device_no, state) in transitions {
+            bus::publish(Event::NodeState { device_no, state });
+        }
+    }
 
-            frame.release();
+    /**
+     * Send v1 frame [header, device no, payload...], encoded in the peer's protocol version
+    */
+    fn send_downstream(&mut self, frame: &[u8], priority: Priority) -> Result<()> {
+        if frame.len() < 2 {
+            bail!("Downstream frame too short: {:02X?}", frame);
+        }
+        let target_no = frame[1] as usize;
 
-            let mut data = [0u8; 10];
-            unsafe{
-                if ESPNOW_LAST_PACKET_LENGTH < 10 {
-                    data[..ESPNOW_LAST_PACKET_LENGTH].copy_from_slice(&ESPNOW_LAST_PACKET[..ESPNOW_LAST_PACKET_LENGTH]);
+        if NODE_ADDRESSES.len() > target_no {
+            let mut data = [0u8; ESPNOW_FRAME_LEN];
+            let (len, seq) = protocol::encode(frame, &mut self.seq_counter, &mut data);
+            let len = signature::sign(target_no, &mut data, len, &mut self.tx_counter)?;
+            let ret = self.espnow.send(NODE_ADDRESSES[target_no], &data[..len]);
+            self.scheduler.charge(len);
+            capture::espnow(Direction::Out, Status::None, &NODE_ADDRESSES[target_no], &data[..len]);
+            match ret {
+                Ok(_) => {
+                    stats::count(&ESPNOW_SENT);
+                    // Send out led indication
+                    bus::publish(Event::Indicator(Indicator::Espnow));
+                    if let Some(seq) = seq {
+                        // Only one frame per node is tracked, the unacknowledged one is reported as failed
+                        if let Some(prev) = protocol::outstanding(target_no) {
+                            if protocol::app_ack(target_no) {
+                                error!("ESPNOW: seq:{prev} to {target_no} not acknowledged, superseded by seq:{seq}");
+                                stats::count(&ESPNOW_FAILED);
+                                bus::publish(Event::SendResult { device_no: target_no as u8, result: SendResult::Failed });
+                            }
+                        }
+                        protocol::mark_outstanding(target_no, seq);
+                        self.ack_retries[target_no] = 0;
+                    }
+                    info!("ESPNOW: downstream latency {}us", DOWNSTREAM_LATENCY.elapsed_us());
+                    // Retry resends the encoded frame, so the sequence number and signature stay the same
+                    self.last_frames[target_no] = (data, len);
+                    self.last_priorities[target_no] = priority;
                 }
-            };
-            let data_len = unsafe{ESPNOW_LAST_PACKET_LENGTH};
+                Err(e) => {
+                bail!("Error sending out espnow msg: {e}");
+                }
+            }
+        }
+        else {
+            error!("This device does not exists! {target_no}");
+        }
+        Ok(())
+    }
+    
+    /**
+     * StatusQuery probe, sent past the scheduler and not tracked for ACK.
+     * A failed probe is neither retried nor reported as /notfound, the reply isn't forwarded upstream.
+    */
+    fn send_probe(&mut self, target_no: usize) -> Result<()> {
+        let mut data = [0u8; ESPNOW_FRAME_LEN];
+        let (len, _) = protocol::encode(&[Msg::StatusQuery as u8, target_no as u8], &mut self.seq_counter, &mut data);
+        let len = signature::sign(target_no, &mut data, len, &mut self.tx_counter)?;
+        self.send_context.mark_probe(target_no as u8, true);
+        let ret = self.send_msg(target_no, &data[..len]);
+        match ret {
+            Ok(_) => liveness::probed(target_no),
+            // No send result will come
+            Err(_) => self.send_context.mark_probe(target_no as u8, false),
+        }
+        ret
+    }
 
-            let target_no = data[1] as usize;
+    /**
+     * When ESPNOW send is failed, retry the last frame sent to the node.
+    */
+    fn send_retry(&mut self, target_no: usize) -> Result<()> {
+        // info!("ESPNOW: retry");
+        if let Some((data, len)) = self.last_frames.get(target_no).copied() {
+            if len > 0 {
+                stats::count(&ESPNOW_RETRIED);
+                self.send_msg(target_no, &data[..len])?;
+            }
+        }
+        Ok(())
+    }
 
-            self.send_msg(target_no, &data[..data_len])?;
+    /**
+     * Resend frames whose app level ACK did not arrive in time, report the device after max retry
+    */
+    pub fn check_app_ack(&mut self) -> Result<()> {
+        for no in 1..NODE_ADDRESSES.len() {
+            if !protocol::app_ack(no) {
+                continue;
+            }
+            let seq = match protocol::outstanding(no) {
+                Some(seq) => seq,
+                None => continue,
+            };
+            match protocol::outstanding_elapsed_us(no) {
+                Some(elapsed) if elapsed >= APP_ACK_TIMEOUT_US => {}
+                _ => continue,
+            }
+
+            if self.ack_retries[no] < ESPNOW_MAX_RETRY {
+                // ACK may have arrived in the meantime
+                if protocol::touch_outstanding(no, seq) {
+                    self.ack_retries[no] += 1;
+                    info!("ESPNOW: no ACK from {no}, resend seq:{seq}");
+                    let (data, len) = self.last_frames[no];
+                    stats::count(&ESPNOW_RETRIED);
+                    self.send_msg(no, &data[..len])?;
+                }
+            }
+            else {
+                error!("ESPNOW: no ACK from {no} for seq:{seq}");
+                protocol::clear_outstanding(no);
+                NODE_STATUS[no].store(NODE_STATUS_FAILED, Ordering::Relaxed);
+                stats::count(&ESPNOW_FAILED);
+                bus::publish(Event::SendResult { device_no: no as u8, result: SendResult::Failed });
+            }
         }
         Ok(())
     }
@@ -130,14 +437,13 @@ impl Espnow{
     fn send_msg(&mut self, target_no:usize, data:&[u8]) -> Result<()> {
         if NODE_ADDRESSES.len() > target_no {
             let ret = self.espnow.send(NODE_ADDRESSES[target_no], data);
+            self.scheduler.charge(data.len());
+            capture::espnow(Direction::Out, Status::None, &NODE_ADDRESSES[target_no], data);
             match ret {
                 Ok(_) => {
+                    stats::count(&ESPNOW_SENT);
                     // Send out led indication
-                    if let Ok(mut wg) = self.led_producer.grant(1){
-                        wg.to_commit(1);
-                        wg[0] = 1;
-                        wg.commit(1);
-                    }
+                    bus::publish(Event::Indicator(Indicator::Espnow));
                 }
                 Err(e) => {
                 bail!("Error sending out espnow msg: {e}");
@@ -151,84 +457,99 @@ impl Espnow{
     }
 
     /**
-     * Sleep the thread until next interval
+     * Time until the first app level ACK times out, None when no frame waits for one
+    */
+    fn next_app_ack_due(&self) -> Option<Duration> {
+        (1..NODE_ADDRESSES.len())
+            .filter(|no| protocol::app_ack(*no))
+            .filter_map(protocol::outstanding_elapsed_us)
+            .map(|elapsed| Duration::from_micros(APP_ACK_TIMEOUT_US.saturating_sub(elapsed) as u64))
+            .min()
+    }
+
+    /**
+     * Block until an event is queued for the thread, a scheduled frame, an app level ACK timeout or an e-stop resend is due
     */
-    pub fn idle(&self) {
-        std::thread::sleep(ESPNOW_FRAME_INTERVAL_MS);
+    pub fn idle(&mut self) {
+        let now = Instant::now();
+        let timeout = [self.scheduler.next_due(now), self.next_app_ack_due(), self.estop.next_due(now)]
+            .into_iter()
+            .flatten()
+            .fold(ESPNOW_IDLE_TIMEOUT, Duration::min);
+        if !timeout.is_zero() {
+            self.events.wait(timeout);
+        }
+    }
+}
+
+fn log_send_error(ret: Result<()>) {
+    if let Err(e) = ret {
+        error!("Failed to send espnow messages: {e}");
     }
 }
 
 /**
  * ESPnow message callback
- * Messages are simply forwarded to UPSTREAM buffer, will be handled in OscSender
+ * Messages are simply published as upstream events, will be handled in OscSender
+ * Signature is checked and stripped here, rejected frames are reported as spoofed.
 */
-fn recv_callback(_recv_info:&[u8], data:&[u8]){
-    info!("espnow:recv_info:{:X?}, data:{:X?}", _recv_info, data);
-    unsafe {if PRODUCER_UPSTREAM.is_some(){
-        let producer = PRODUCER_UPSTREAM.as_mut().unwrap();
-        let sz = data.len();
-        if let Ok(mut wg) = producer.grant(sz){
-            wg.to_commit(sz);
-            wg.copy_from_slice(data);
-            wg.commit(sz);
+fn on_recv<S: Sink>(context: &RecvContext<S>, mac_addr: &[u8], data: &[u8]) {
+    info!("espnow:recv_info:{:X?}, data:{:X?}", mac_addr, data);
+    let dev_no = device_no(mac_addr);
+    let data = match signature::verify(dev_no as usize, mac_addr, data) {
+        Ok(len) => {
+            capture::espnow(Direction::In, Status::None, mac_addr, data);
+            &data[..len]
         }
-        else{
-            error!("ESPNOW:UpStream Buffer Overflow!");
+        Err(reason) => {
+            capture::espnow(Direction::In, Status::Rejected, mac_addr, data);
+            let count = SPOOF_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            error!("ESPNOW: rejected frame from {:X?}: {:?} ({count})", mac_addr, reason);
+            context.spoof(mac_addr);
+            return;
         }
-
-    }}
+    };
+    if dev_no != 0 {
+        NODE_STATUS[dev_no as usize].store(NODE_STATUS_OK, Ordering::Relaxed);
+        liveness::seen(dev_no as usize);
+    }
+    if !context.upstream(data) {
+        error!("ESPNOW: upstream frame dropped ({})", context.drops());
+    }
 }
 
 /**
  * ESPnow send callback. When espnow send is completed, determine the SendStatus
  * and give back the error osc message when the espnow messge did not reach to destination.
 */
-fn send_callback(mac_addr:&[u8], send_status: SendStatus){
+fn on_send<S: Sink>(context: &SendContext<S>, mac_addr: &[u8], send_status: SendStatus) {
+    let dev_no = device_no(mac_addr);
     match send_status {
         SendStatus::SUCCESS => {
             info!("send to {:X?} succesfull", mac_addr);
-            unsafe{ESPNOW_RETRY_COUNT = 0;}
+            capture::espnow(Direction::Out, Status::Delivered, mac_addr, &[]);
+            NODE_STATUS[dev_no as usize].store(NODE_STATUS_OK, Ordering::Relaxed);
+            liveness::seen(dev_no as usize);
+            stats::count(&ESPNOW_DELIVERED);
+            // App level ACK peers stay outstanding until the node acknowledges
+            if !protocol::app_ack(dev_no as usize) {
+                protocol::clear_outstanding(dev_no as usize);
+            }
+            context.delivered(dev_no);
         }
         SendStatus::FAIL => {
             error!("ESPNOW:sending to {:X?} failed!", mac_addr);
-
-            // Find device no
-            let mut dev_no:u8 = 0;
-            for (i, n) in NODE_ADDRESSES.iter().enumerate(){
-                if n == mac_addr{
-                    dev_no = i as u8;
-                    break;
-                }
+            capture::espnow(Direction::Out, Status::Failed, mac_addr, &[]);
+            if dev_no == 0 {
+                return;
             }
 
-            if dev_no != 0{
-                // Retry
-                unsafe {
-                    if ESPNOW_RETRY_COUNT < ESPNOW_MAX_RETRY{
-                        if PRODUCER_ESPNOWRETRY.is_some(){
-                            ESPNOW_RETRY_COUNT += 1;
-                            let producer = PRODUCER_ESPNOWRETRY.as_mut().unwrap();
-                            if let Ok(mut wg) = producer.grant(1){
-                                wg.to_commit(1);
-                                wg[0] = dev_no;
-                                wg.commit(1);
-                            }
-                        }
-                    }
-                    // Send error on retry failure
-                    else {
-                        if PRODUCER_SENDERROR.is_some(){
-                            let producer = PRODUCER_SENDERROR.as_mut().unwrap();
-                            if let Ok(mut wg) = producer.grant(1){
-                                wg.to_commit(1);
-                                wg[0] = dev_no;
-                                wg.commit(1);
-                            }
-                        }
-                    }
-                }
+            // Send error on retry failure
+            if context.failed(dev_no) == SendResult::Failed {
+                NODE_STATUS[dev_no as usize].store(NODE_STATUS_FAILED, Ordering::Relaxed);
+                stats::count(&ESPNOW_FAILED);
+                protocol::clear_outstanding(dev_no as usize);
             }
-
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,132 @@
+use log::*;
+
+use std::time::{Duration, Instant};
+
+use crate::espnow::NODE_ADDRESSES;
+use crate::osc::Msg;
+
+// Unicast resend interval to nodes that haven't acknowledged, until the deadline, then the slow interval
+const ESTOP_RESEND_INTERVAL: Duration = Duration::from_millis(100);
+const ESTOP_SLOW_RESEND_INTERVAL: Duration = Duration::from_secs(1);
+// Nodes that haven't acknowledged by then are reported missing
+const ESTOP_ACK_DEADLINE: Duration = Duration::from_secs(2);
+
+const NODE_COUNT: usize = NODE_ADDRESSES.len();
+
+/**
+ * E-stop frame to the node: [Estop or EstopRelease, device no], device no 0 is broadcast
+*/
+fn frame(latched: bool, device_no: usize) -> Vec<u8> {
+    let header = if latched { Msg::Estop } else { Msg::EstopRelease };
+    vec![header as u8, device_no as u8]
+}
+
+/**
+ * E-stop state of the ESPNOW thread.
+ * Stop and release are broadcast once, then unicast to every node until it acknowledges with
+ * [EstopAck, device no, latched]. Nodes booting while latched get the stop again.
+*/
+pub struct Estop {
+    latched: bool,
+    // Nodes that haven't acknowledged the current state
+    pending: [bool; NODE_COUNT],
+    started: Instant,
+    resent: Instant,
+    missing_reported: bool,
+}
+
+impl Estop {
+    pub fn new() -> Self {
+        Self {
+            latched: false,
+            pending: [false; NODE_COUNT],
+            started: Instant::now(),
+            resent: Instant::now(),
+            missing_reported: false,
+        }
+    }
+
+    /**
+     * Latch or release. Returns frames to send: broadcast, then each node.
+    */
+    pub fn command(&mut self, latched: bool, now: Instant) -> Vec<Vec<u8>> {
+        info!("E-stop {}", if latched { "latched" } else { "released" });
+        self.latched = latched;
+        self.started = now;
+        self.resent = now;
+        self.missing_reported = false;
+        let mut frames = vec![frame(latched, 0)];
+        // Broadcast address is not a node
+        for no in 1..NODE_COUNT {
+            self.pending[no] = true;
+            frames.push(frame(latched, no));
+        }
+        frames
+    }
+
+    /**
+     * Acknowledgement from the node. Returns true when it confirms the current state for the first time.
+    */
+    pub fn ack(&mut self, device_no: usize, latched: bool) -> bool {
+        if device_no == 0 || device_no >= NODE_COUNT || latched != self.latched || !self.pending[device_no] {
+            return false;
+        }
+        self.pending[device_no] = false;
+        true
+    }
+
+    /**
+     * Node booted, its e-stop state is lost. Returns the stop frame when latched.
+    */
+    pub fn boot(&mut self, device_no: usize) -> Option<Vec<u8>> {
+        if !self.latched || device_no == 0 || device_no >= NODE_COUNT {
+            return None;
+        }
+        info!("E-stop: node {device_no} booted while latched, resend");
+        self.pending[device_no] = true;
+        Some(frame(true, device_no))
+    }
+
+    /**
+     * Frames to resend to nodes that haven't acknowledged, and the nodes to report missing once the deadline passed
+    */
+    pub fn check(&mut self, now: Instant) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        let pending: Vec<usize> = (1..NODE_COUNT).filter(|no| self.pending[*no]).collect();
+        if pending.is_empty() {
+            return (Vec::new(), None);
+        }
+
+        let late = now.duration_since(self.started) >= ESTOP_ACK_DEADLINE;
+        let missing = if late && !self.missing_reported {
+            self.missing_reported = true;
+            error!("E-stop: no ACK from {:?}", pending);
+            Some(pending.iter().map(|no| *no as u8).collect())
+        } else {
+            None
+        };
+
+        let interval = if late { ESTOP_SLOW_RESEND_INTERVAL } else { ESTOP_RESEND_INTERVAL };
+        if now.duration_since(self.resent) < interval {
+            return (Vec::new(), missing);
+        }
+        self.resent = now;
+        (pending.into_iter().map(|no| frame(self.latched, no)).collect(), missing)
+    }
+
+    /**
+     * Time until check has a resend or the missing report, None when every node acknowledged
+    */
+    pub fn next_due(&self, now: Instant) -> Option<Duration> {
+        if !self.pending.iter().any(|p| *p) {
+            return None;
+        }
+        let elapsed = now.duration_since(self.started);
+        let interval = if elapsed >= ESTOP_ACK_DEADLINE { ESTOP_SLOW_RESEND_INTERVAL } else { ESTOP_RESEND_INTERVAL };
+        let resend = interval.saturating_sub(now.duration_since(self.resent));
+        if self.missing_reported {
+            Some(resend)
+        } else {
+            Some(resend.min(ESTOP_ACK_DEADLINE.saturating_sub(elapsed)))
+        }
+    }
+}
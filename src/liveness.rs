@@ -0,0 +1,160 @@
+use log::*;
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::espnow::NODE_ADDRESSES;
+
+pub const NODE_QUERY_INTERVAL_DEFAULT: Duration = Duration::from_secs(5);
+pub const NODE_OFFLINE_TIMEOUT_DEFAULT: Duration = Duration::from_secs(15);
+// Probe interval of offline nodes doubles after each probe up to this
+const NODE_PROBE_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+const NODE_COUNT: usize = NODE_ADDRESSES.len();
+
+// Frames and MAC ACKs received from each node, counted in the ESPNOW callbacks
+#[allow(clippy::declare_interior_mutable_const)]
+const SEEN_COUNT_INIT: AtomicU32 = AtomicU32::new(0);
+static SEEN_COUNT: [AtomicU32; NODE_COUNT] = [SEEN_COUNT_INIT; NODE_COUNT];
+
+// Probe sent to the node, its status reply stays in the station
+#[allow(clippy::declare_interior_mutable_const)]
+const PROBED_INIT: AtomicBool = AtomicBool::new(false);
+static PROBED: [AtomicBool; NODE_COUNT] = [PROBED_INIT; NODE_COUNT];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeState {
+    Unknown,
+    Online,
+    Offline,
+}
+
+impl NodeState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeState::Unknown => "unknown",
+            NodeState::Online => "online",
+            NodeState::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct NodeInfo {
+    pub state: NodeState,
+    pub last_seen: Option<Instant>,
+}
+
+// Updated by the ESPNOW thread, read for the /nodes query
+static NODES: Mutex<[NodeInfo; NODE_COUNT]> = Mutex::new([NodeInfo { state: NodeState::Unknown, last_seen: None }; NODE_COUNT]);
+
+/**
+ * Node is alive, any frame or MAC ACK from it counts as heartbeat
+*/
+pub fn seen(device_no: usize) {
+    if let Some(count) = SEEN_COUNT.get(device_no) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Liveness probe sent to the node
+*/
+pub fn probed(device_no: usize) {
+    if let Some(p) = PROBED.get(device_no) {
+        p.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+ * StatusQuery sent on request, the reply goes upstream
+*/
+pub fn queried(device_no: usize) {
+    if let Some(p) = PROBED.get(device_no) {
+        p.store(false, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Status reply from the node answers a probe, the heartbeat is already counted
+*/
+pub fn probe_reply(device_no: usize) -> bool {
+    PROBED.get(device_no).map_or(false, |p| p.swap(false, Ordering::Relaxed))
+}
+
+pub fn nodes() -> [NodeInfo; NODE_COUNT] {
+    *NODES.lock().unwrap()
+}
+
+/**
+ * Liveness check of the ESPNOW thread.
+ * Nodes quiet for a query interval are probed with StatusQuery, nodes quiet for the offline timeout are offline.
+ * Offline nodes are probed less and less often, until they are seen again.
+*/
+pub struct Liveness {
+    query_interval: Duration,
+    offline_timeout: Duration,
+    seen_counts: [u32; NODE_COUNT],
+    started: Instant,
+    last_probes: [Instant; NODE_COUNT],
+    probe_intervals: [Duration; NODE_COUNT],
+}
+
+impl Liveness {
+    pub fn new(query_interval: Duration, offline_timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            query_interval,
+            offline_timeout,
+            seen_counts: [0; NODE_COUNT],
+            started: now,
+            last_probes: [now; NODE_COUNT],
+            probe_intervals: [query_interval; NODE_COUNT],
+        }
+    }
+
+    /**
+     * Update node states. Returns the nodes to probe and the state transitions.
+     * Nodes never seen go offline after the timeout from the start.
+    */
+    pub fn check(&mut self, now: Instant) -> (Vec<u8>, Vec<(u8, NodeState)>) {
+        let mut transitions = Vec::new();
+        let mut probes = Vec::new();
+
+        let mut nodes = NODES.lock().unwrap();
+        // Broadcast address is not a node
+        for no in 1..NODE_COUNT {
+            let node = &mut nodes[no];
+            let count = SEEN_COUNT[no].load(Ordering::Relaxed);
+            if count != self.seen_counts[no] {
+                self.seen_counts[no] = count;
+                node.last_seen = Some(now);
+                self.probe_intervals[no] = self.query_interval;
+            }
+
+            let quiet = now.duration_since(node.last_seen.unwrap_or(self.started));
+            let state = if quiet >= self.offline_timeout {
+                NodeState::Offline
+            } else if node.last_seen.is_some() {
+                NodeState::Online
+            } else {
+                node.state
+            };
+            if state != node.state {
+                info!("Node {no} {}", state.as_str());
+                node.state = state;
+                transitions.push((no as u8, state));
+            }
+
+            if quiet >= self.query_interval && now.duration_since(self.last_probes[no]) >= self.probe_intervals[no] {
+                self.last_probes[no] = now;
+                if state == NodeState::Offline {
+                    self.probe_intervals[no] = (self.probe_intervals[no] * 2).min(NODE_PROBE_BACKOFF_MAX.max(self.query_interval));
+                }
+                probes.push(no as u8);
+            }
+        }
+        (probes, transitions)
+    }
+}
@@ -7,10 +7,9 @@ use std::time::Duration;
 use esp_idf_sys::{self as _}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
-use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, netif};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
+use esp_idf_svc::eth::EthEvent;
 use embedded_svc::wifi::{AuthMethod, Configuration, AccessPointConfiguration};
-use embedded_svc::ipv4;
-use embedded_svc::ipv4::{ClientConfiguration, ClientSettings, Subnet, Mask};
 
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_hal::gpio;
@@ -18,39 +17,78 @@ use esp_idf_hal::gpio;
 #[cfg(feature = "W5500")]
 use esp_idf_hal::spi;
 
+#[cfg(feature = "midi-serial")]
+use esp_idf_hal::{uart, units::Hertz};
+
 use esp_idf_hal::gpio::*;
 
 use std::net::Ipv4Addr;
 use std::str::FromStr;
 
-use bbqueue::BBBuffer;
-use bbqueue::framed::FrameProducer;
-
 mod osc;
-use osc::{*, MSG_BUF_UPTREAM, MSG_BUF_DOWNSTREAM, MSG_BUF_LED, MSG_BUF_ERROR, MSG_BUF_IP};
+use osc::*;
 
 mod espnow;
 use espnow::Espnow;
 
-static QUEUE_DOWNSTREAM: BBBuffer<MSG_BUF_DOWNSTREAM>= BBBuffer::new();
-static QUEUE_UPSTREAM: BBBuffer<MSG_BUF_UPTREAM>= BBBuffer::new();
-static QUEUE_LED: BBBuffer<MSG_BUF_LED>= BBBuffer::new();
-static QUEUE_LED1: BBBuffer<MSG_BUF_LED>= BBBuffer::new();
-static QUEUE_ERROR: BBBuffer<MSG_BUF_ERROR>= BBBuffer::new();
-static QUEUE_DEST_IP: BBBuffer<MSG_BUF_IP>= BBBuffer::new();
-
-static QUEUE_ESPNOWRETRY: BBBuffer<MSG_BUF_ESPNOWRETRY>= BBBuffer::new();
-static mut ESPNOW_RETRY_COUNT:usize = 0;
-static mut ESPNOW_LAST_PACKET: [u8;10] = [0u8;10];
-static mut ESPNOW_LAST_PACKET_LENGTH: usize = 0;
-pub const MSG_BUF_ESPNOWRETRY: usize = 4;
+mod protocol;
+
+mod config;
+use config::Config;
+
+mod auth;
+use auth::Auth;
+
+mod signature;
+use signature::TxCounter;
+
+mod scheduler;
+
+mod estop;
+
+mod liveness;
+use liveness::Liveness;
+
+mod stats;
+
+mod netlog;
+use netlog::Forwarder;
+
+mod capture;
+mod web;
+
+mod network;
+use network::IpSettings;
+
+mod discovery;
+use discovery::{Discovery, Resolver};
+
+mod radio;
+use radio::RadioSettings;
+
+mod bus;
+use bus::{Event, Indicator, Overflow};
+use std::sync::{Arc, Mutex};
+
+#[cfg(any(feature = "midi", feature = "artnet", feature = "sacn"))]
+mod poll;
+
+#[cfg(feature = "midi")]
+mod midi;
+#[cfg(feature = "midi")]
+use midi::MidiBridge;
+
+#[cfg(any(feature = "artnet", feature = "sacn"))]
+mod dmx;
+#[cfg(any(feature = "artnet", feature = "sacn"))]
+use dmx::DmxBridge;
+
 const ESPNOW_MAX_RETRY: usize = 3;
 
-static mut PRODUCER_UPSTREAM: Option<FrameProducer<MSG_BUF_UPTREAM>> = None;
-static mut PRODUCER_SENDERROR: Option<FrameProducer<MSG_BUF_ERROR>> = None;
-static mut PRODUCER_ESPNOWRETRY: Option<FrameProducer<MSG_BUF_ERROR>> = None;
 
 static LED_SLEEP_DURATION_MS: Duration = Duration::from_millis(50);
+static LED_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+const LED_EVENT_CAPACITY: usize = 16;
 
 #[allow(dead_code)]
 const RECV_PORT_STR: &str = env!("OSC_RECV_PORT");
@@ -81,14 +119,70 @@ const LOCAL_IP: &str = env!("OSC_LOCAL_IP");
 const PEER_CHANNEL_STR: &str = env!("ESPNOW_CHANNEL");
 // const PEER_CHANNEL: u8 = 0u8;
 
+// Optional security for privileged commands (/reset, /setdestip, /setlmk...)
+// Config commands are disabled unless one of them is set
+const ALLOW_LIST: Option<&str> = option_env!("OSC_ALLOW");
+const HMAC_KEY: Option<&str> = option_env!("OSC_HMAC_KEY");
+
+// Optional OSC addresses sent as high priority, e.g. "/reset,/run". Defaults are in osc.rs
+const HIGH_PRIORITY: Option<&str> = option_env!("OSC_HIGH_PRIORITY");
+
+// Optional overflow policy of the event queues, e.g. "espnow=block:10,osc=drop-oldest"
+const QUEUE_POLICY: Option<&str> = option_env!("QUEUE_POLICY");
+
+// Optional node liveness timing in ms, defaults are in liveness.rs
+const NODE_QUERY_INTERVAL_STR: Option<&str> = option_env!("NODE_QUERY_INTERVAL_MS");
+const NODE_OFFLINE_TIMEOUT_STR: Option<&str> = option_env!("NODE_OFFLINE_TIMEOUT_MS");
+
+// Optional log forwarding: "osc" (/log to the OSC destination) or "syslog:<ip>[:<port>]", level defaults to warn
+const LOG_FORWARD: Option<&str> = option_env!("LOG_FORWARD");
+const LOG_FORWARD_LEVEL: &str = match option_env!("LOG_FORWARD_LEVEL") {
+    Some(level) => level,
+    None => "warn",
+};
+
+// Optional capture download over HTTP, off unless set. Not authenticated, anyone reaching port 80 can download.
+const CAPTURE_HTTP: bool = option_env!("CAPTURE_HTTP").is_some();
+
+// Optional mDNS names and station id (TXT record), the id defaults to the ESP-NOW MAC
+const MDNS_HOSTNAME: &str = match option_env!("MDNS_HOSTNAME") {
+    Some(hostname) => hostname,
+    None => "espnow-station",
+};
+const MDNS_INSTANCE: &str = match option_env!("MDNS_INSTANCE") {
+    Some(instance) => instance,
+    None => "ESPNOW OSC Station",
+};
+const STATION_ID: Option<&str> = option_env!("STATION_ID");
+
+// Optional WiFi station fallback (WPA2) when the Ethernet link is not up within the timeout in ms, default is in network.rs
+const WIFI_STA_SSID: Option<&str> = option_env!("WIFI_STA_SSID");
+const WIFI_STA_PASSWORD: Option<&str> = option_env!("WIFI_STA_PASSWORD");
+const ETH_LINK_TIMEOUT_STR: Option<&str> = option_env!("ETH_LINK_TIMEOUT_MS");
+
+// Optional interval in ms to resolve the destination hostname again, default is in discovery.rs
+const DEST_RESOLVE_INTERVAL_STR: Option<&str> = option_env!("DEST_RESOLVE_INTERVAL_MS");
+
+// Optional periodic /stats push in ms, only answered to /stats queries when not set
+const STATS_INTERVAL_STR: Option<&str> = option_env!("STATS_INTERVAL_MS");
+
+// Optional, defaults are in midi.rs
+#[cfg(feature = "midi")]
+const MIDI_RTP_PORT_STR: Option<&str> = option_env!("MIDI_RTP_PORT");
+#[cfg(feature = "midi-serial")]
+const MIDI_SERIAL_BAUD_STR: Option<&str> = option_env!("MIDI_SERIAL_BAUD");
+
 fn main()-> Result<()> {
     esp_idf_sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // EspLogger to the UART, and records forwarded over the network once the forwarder starts
+    netlog::initialize();
     unsafe{
         esp_idf_sys::nvs_flash_init();
     }
     let nvs = EspDefaultNvsPartition::take().unwrap();
     let sysloop = EspSystemEventLoop::take().unwrap();
+    let config = Arc::new(Mutex::new(Config::new(nvs.clone())?));
+    netlog::load_levels(&config.lock().unwrap());
 
     // Pin Config
     let peripherals = Peripherals::take().unwrap();
@@ -98,22 +192,40 @@ fn main()-> Result<()> {
     led.set_low()?;
     led1.set_low()?;
 
+    #[cfg(feature = "midi-serial")]
+    let midi_uart = {
+        let baud = MIDI_SERIAL_BAUD_STR.map_or(midi::MIDI_SERIAL_BAUD_DEFAULT, |b| b.parse::<u32>().unwrap());
+        uart::UartDriver::new(
+            peripherals.uart1,
+            peripherals.pins.gpio4,
+            peripherals.pins.gpio36,
+            Option::<gpio::AnyIOPin>::None,
+            Option::<gpio::AnyIOPin>::None,
+            &uart::config::Config::new().baudrate(Hertz(baud)),
+        )?
+    };
+
     // Wifi / ESPNow setting
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs)).unwrap(),
+        EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs.clone())).unwrap(),
         sysloop.clone(),
     ).unwrap();
 
-    let wifi_configuration: Configuration = Configuration::AccessPoint(AccessPointConfiguration{
+    // Channel, TX power, PHY and country from NVS, ESPNOW_CHANNEL until they are stored
+    let radio_settings = RadioSettings::load(&config.lock().unwrap(), PEER_CHANNEL_STR.parse::<u8>().unwrap());
+    let ap_configuration = AccessPointConfiguration{
         ssid: "espnow".into(),
         ssid_hidden: true,
-        channel: 0,
+        channel: radio_settings.channel,
         auth_method: AuthMethod::None,
         ..Default::default()
-    }) ;
-    wifi.set_configuration(&wifi_configuration)?;
+    };
+    wifi.set_configuration(&Configuration::AccessPoint(ap_configuration.clone()))?;
     wifi.start()?;
     info!("Is Wifi started? {:?}", wifi.is_started());
+    if let Err(e) = radio_settings.apply() {
+        error!("Failed to apply radio settings: {e}");
+    }
 
     wifi.wait_netif_up()?;
     info!("Wifi netif up");
@@ -121,9 +233,10 @@ fn main()-> Result<()> {
     let mac = wifi.wifi().ap_netif().get_mac()?;
     info!("mac address: {:X?}", mac);
 
-    // Ethernet Config
+    // Ethernet Config, the build's addresses are used until IP settings are stored in NVS
     let local_ip = Ipv4Addr::from_str(LOCAL_IP)?;
     let gateway_ip = Ipv4Addr::from_str(GATEWAY_IP)?;
+    let ip_settings = IpSettings::load(&config.lock().unwrap(), local_ip, gateway_ip);
 
     #[cfg(feature = "LAN870")]
     let eth_driver =
@@ -171,64 +284,92 @@ fn main()-> Result<()> {
     };
 
 
-    let mut eth_config = netif::NetifConfiguration::eth_default_client();
-    let ipconfig = ipv4::Configuration::Client(
-        ClientConfiguration::Fixed(
-            ClientSettings {
-            ip: local_ip,
-            subnet: Subnet {
-                gateway: gateway_ip,
-                mask: Mask(24),
-            },
-            dns: None,
-            secondary_dns: None,
-            },
-        ));
-    eth_config.ip_configuration = ipconfig;
-    let eth_netif = netif::EspNetif::new_with_conf(&eth_config)?;
+    let eth_netif = network::eth_netif(&ip_settings)?;
     let mut eth = Box::new(
         esp_idf_svc::eth::EspEth::wrap_all(eth_driver, eth_netif)?
     );
-    let local_ip = eth_configure(&sysloop, &mut eth)?;
+    // Link timeout only applies with the WiFi station fallback
+    let eth_link_timeout = WIFI_STA_SSID.map(|_| ETH_LINK_TIMEOUT_STR.map_or(network::ETH_LINK_TIMEOUT_DEFAULT, |t| Duration::from_millis(t.parse::<u64>().unwrap())));
+    let (local_ip, addressing, sta_channel) = match network::eth_configure(&sysloop, &mut eth, &ip_settings, eth_link_timeout)? {
+        Some((ip, ip_mode)) => {
+            stats::ETH_UP.store(true, std::sync::atomic::Ordering::Relaxed);
+            (ip, ip_mode.as_str(), None)
+        }
+        None => {
+            let ssid = WIFI_STA_SSID.unwrap();
+            let (ip, channel) = network::wifi_sta_configure(&mut wifi, ssid, WIFI_STA_PASSWORD.unwrap_or(""), ap_configuration)?;
+            (ip, "wifi", Some(channel))
+        }
+    };
+    let _eth_subscription = sysloop.subscribe(|event: &EthEvent| {
+        match event {
+            EthEvent::Connected(_) => stats::ETH_UP.store(true, std::sync::atomic::Ordering::Relaxed),
+            EthEvent::Disconnected(_) | EthEvent::Stopped(_) => stats::ETH_UP.store(false, std::sync::atomic::Ordering::Relaxed),
+            _ => {}
+        }
+    })?;
+    #[cfg(feature = "artnet")]
+    let eth_mac = eth.netif().get_mac()?;
 
     info!("ESPNOW Bridge started");
 
-    let dest_ip = Ipv4Addr::from_str(DEST_IP)?;
-    // let dest_ip2 = Ipv4Addr::from_str(DEST_IP2)?;
-
-    // Thread communication buffers!
-    let (downstream_msg_producer, downstream_msg_consumer) = QUEUE_DOWNSTREAM.try_split_framed().unwrap();
-    let (upstream_msg_producer, upstream_msg_consumer) = QUEUE_UPSTREAM.try_split_framed().unwrap();
-    unsafe {PRODUCER_UPSTREAM = Some(upstream_msg_producer);}
-    let (led_msg_producer, mut led_msg_consumer) = QUEUE_LED.try_split_framed().unwrap();
-    let (led1_msg_producer, mut led1_msg_consumer) = QUEUE_LED1.try_split_framed().unwrap();
+    let recv_port = RECV_PORT_STR.parse::<u16>().unwrap();
+    let send_port = SEND_PORT_STR.parse::<u16>().unwrap();
+    let dest_port = DEST_PORT_STR.parse::<u16>().unwrap();
+    // As station, ESP-NOW is on the channel of the WiFi network
+    let peer_channel = match (radio_settings.channel, sta_channel) {
+        (configured, Some(channel)) => {
+            if configured != 0 && configured != channel {
+                warn!("Radio channel {configured} replaced by the WiFi channel {channel}, nodes have to use it");
+            }
+            channel
+        }
+        (configured, None) => configured,
+    };
 
-    let (send_error_msg_producer, send_error_msg_consumer) = QUEUE_ERROR.try_split_framed().unwrap();
-    unsafe {PRODUCER_SENDERROR = Some(send_error_msg_producer);}
+    let station_id = match STATION_ID {
+        Some(id) => id.to_string(),
+        None => mac.iter().map(|b| format!("{b:02X}")).collect(),
+    };
+    let _discovery = Discovery::start(MDNS_HOSTNAME, MDNS_INSTANCE, recv_port, &station_id, peer_channel, CAPTURE_HTTP)?;
 
-    let (espnow_retry_msg_producer, espnow_retry_msg_consumer) = QUEUE_ESPNOWRETRY.try_split_framed().unwrap();
-    unsafe {PRODUCER_ESPNOWRETRY = Some(espnow_retry_msg_producer);}
+    // IPv4 or hostname, resolved again by the resolver thread
+    let dest_ip = discovery::resolve(DEST_IP).unwrap_or_else(|e| {
+        error!("Destination not resolved yet: {e}");
+        Ipv4Addr::UNSPECIFIED
+    });
+    // let dest_ip2 = Ipv4Addr::from_str(DEST_IP2)?;
 
-    let (destip_msg_producer, destip_msg_consumer) = QUEUE_DEST_IP.try_split_framed().unwrap();
+    // Thread communication, subscribe before any thread publishes
+    let espnow_events = Espnow::subscribe();
+    let osc_sender_events = OscSender::subscribe();
+    #[cfg(feature = "midi")]
+    let midi_events = MidiBridge::subscribe();
+    let log_events = LOG_FORWARD.map(|_| Forwarder::subscribe());
+    let resolver_events = Resolver::subscribe();
+    let led_events = bus::subscribe("led", LED_EVENT_CAPACITY, Overflow::DropNewest, |e| matches!(e, Event::Indicator(Indicator::Espnow)));
+    let led1_events = bus::subscribe("led1", LED_EVENT_CAPACITY, Overflow::DropNewest, |e| matches!(e, Event::Indicator(Indicator::Osc)));
+    bus::configure(QUEUE_POLICY)?;
 
-    let recv_port = RECV_PORT_STR.parse::<u16>().unwrap();
-    let send_port = SEND_PORT_STR.parse::<u16>().unwrap();
-    let dest_port = DEST_PORT_STR.parse::<u16>().unwrap();
-    let peer_channel = PEER_CHANNEL_STR.parse::<u8>().unwrap();
+    let auth = Auth::new(ALLOW_LIST, HMAC_KEY, config.clone())?;
 
     // Create thread to handle ESPNow messages
+    let espnow_config = config.clone();
+    let tx_counter = TxCounter::new(config.clone())?;
+    let liveness = Liveness::new(
+        NODE_QUERY_INTERVAL_STR.map_or(liveness::NODE_QUERY_INTERVAL_DEFAULT, |t| Duration::from_millis(t.parse::<u64>().unwrap())),
+        NODE_OFFLINE_TIMEOUT_STR.map_or(liveness::NODE_OFFLINE_TIMEOUT_DEFAULT, |t| Duration::from_millis(t.parse::<u64>().unwrap())),
+    );
     let espnow_join_handle = std::thread::Builder::new()
         .stack_size(4096)
         .spawn(move || {
-            let mut espnow = Espnow::new(downstream_msg_consumer, led_msg_producer, espnow_retry_msg_consumer);
+            let mut espnow = Espnow::new(espnow_events, tx_counter, liveness, espnow_config);
             espnow.config(peer_channel);
 
             loop {
-                if let Err(e) = espnow.run() {
-                    error!("Failed to send espnow messages: {e}");
-                }
-                if let Err(e) = espnow.send_retry() {
-                    error!("Failed to run ESPNOW resend: {e}");
+                espnow.run();
+                if let Err(e) = espnow.check_app_ack() {
+                    error!("Failed to run ESPNOW ACK resend: {e}");
                 }
                 espnow.idle();
             }
@@ -237,23 +378,69 @@ fn main()-> Result<()> {
         let osc_receiver_join_handle = std::thread::Builder::new()
         .stack_size(8192)
         .spawn(move || {
-            let mut osc = OscReceiver::new(local_ip, recv_port, downstream_msg_producer, destip_msg_producer);
+            let mut osc = OscReceiver::new(local_ip, recv_port, config, auth, HIGH_PRIORITY, ip_settings, radio_settings);
             loop {
                 if let Err(e) = osc.run() {
                         error!("Failed to run OSC: {e}");
                         // break;
+                        osc.idle();
+                    }
+            }
+        })?;
+
+    if let (Some(forward), Some(log_events)) = (LOG_FORWARD, log_events) {
+        match Forwarder::new(forward, LOG_FORWARD_LEVEL, local_ip, std::net::SocketAddrV4::new(dest_ip, dest_port), log_events) {
+            Ok(mut forwarder) => {
+                std::thread::Builder::new()
+                .stack_size(4096)
+                .spawn(move || {
+                    loop {
+                        if let Err(e) = forwarder.run() {
+                                error!("Failed to forward logs: {e}");
+                            }
+                        forwarder.idle();
+                    }
+                })?;
+            }
+            // The station runs without log forwarding rather than not at all
+            Err(e) => error!("Log forwarding disabled: {e}"),
+        }
+    }
+
+    let dest_resolve_interval = DEST_RESOLVE_INTERVAL_STR.map_or(discovery::DEST_RESOLVE_INTERVAL_DEFAULT, |t| Duration::from_millis(t.parse::<u64>().unwrap()));
+    let mut resolver = Resolver::new(DEST_IP, dest_ip, dest_resolve_interval, resolver_events);
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            loop {
+                if let Err(e) = resolver.run() {
+                        warn!("Failed to resolve destination: {e}");
                     }
-                osc.idle();
+                resolver.idle();
             }
         })?;
 
+    // Capture download over HTTP, and UDP stream
+    let _http_server = if CAPTURE_HTTP { Some(web::start()?) } else { None };
+    let mut capture_streamer = capture::Streamer::new(local_ip)?;
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            loop {
+                if let Err(e) = capture_streamer.run() {
+                        error!("Failed to stream capture: {e}");
+                    }
+                capture_streamer.idle();
+            }
+        })?;
+
+    let stats_interval = STATS_INTERVAL_STR.map(|t| Duration::from_millis(t.parse::<u64>().unwrap()));
     let osc_sender_join_handle = std::thread::Builder::new()
         .stack_size(8192)
         .spawn(move || {
-            let mut osc_sender = OscSender::new(dest_ip, dest_port, local_ip, send_port, upstream_msg_consumer
-            // let mut osc_sender = OscSender::new(dest_ip, dest_ip2, DEST_PORT, local_ip, SEND_PORT, upstream_msg_consumer
-                , led1_msg_producer, send_error_msg_consumer, destip_msg_consumer);
-            osc_sender.send_bootmsg().unwrap();
+            let mut osc_sender = OscSender::new(dest_ip, dest_port, local_ip, send_port, osc_sender_events, stats_interval);
+            // let mut osc_sender = OscSender::new(dest_ip, dest_ip2, DEST_PORT, local_ip, SEND_PORT, osc_sender_events);
+            osc_sender.send_bootmsg(local_ip, addressing).unwrap();
             loop {
                 if let Err(e) = osc_sender.run() {
                         error!("Failed to run OSC Sender: {e}");
@@ -263,17 +450,53 @@ fn main()-> Result<()> {
             }
         })?;
 
+    #[cfg(feature = "midi")]
+    let midi_join_handle = {
+        let midi_rtp_port = MIDI_RTP_PORT_STR.map_or(midi::MIDI_RTP_PORT_DEFAULT, |p| p.parse::<u16>().unwrap());
+        std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || {
+            let mut midi = MidiBridge::new(local_ip, midi_rtp_port, midi_events,
+                #[cfg(feature = "midi-serial")] midi_uart);
+            loop {
+                if let Err(e) = midi.run() {
+                        error!("Failed to run MIDI bridge: {e}");
+                        midi.idle();
+                    }
+            }
+        })?
+    };
+
+    #[cfg(any(feature = "artnet", feature = "sacn"))]
+    let dmx_join_handle = std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || {
+            let mut dmx = DmxBridge::new(local_ip,
+                #[cfg(feature = "artnet")] eth_mac);
+            loop {
+                if let Err(e) = dmx.run() {
+                        error!("Failed to run DMX bridge: {e}");
+                        dmx.idle();
+                    }
+            }
+        })?;
+
     let led_join_handle = std::thread::Builder::new()
         .stack_size(1024)
         .spawn(move || {
             loop {
-                if let Some(frame) = led_msg_consumer.read() {
-                    frame.release();
+                led_events.wait(LED_IDLE_TIMEOUT);
+                // One blink for all frames since the last one
+                let mut blink = false;
+                while led_events.try_recv().is_some() {
+                    blink = true;
+                }
+                if blink {
                     let _ = led.set_high();
                     std::thread::sleep(LED_SLEEP_DURATION_MS);
                     let _ = led.set_low();
+                    std::thread::sleep(LED_SLEEP_DURATION_MS);
                 }
-                std::thread::sleep(LED_SLEEP_DURATION_MS);
             }
         })?;
 
@@ -281,40 +504,30 @@ fn main()-> Result<()> {
         .stack_size(1024)
         .spawn(move || {
             loop {
-                if let Some(frame) = led1_msg_consumer.read() {
-                    frame.release();
+                led1_events.wait(LED_IDLE_TIMEOUT);
+                let mut blink = false;
+                while led1_events.try_recv().is_some() {
+                    blink = true;
+                }
+                if blink {
                     let _ = led1.set_high();
                     std::thread::sleep(LED_SLEEP_DURATION_MS);
                     let _ = led1.set_low();
+                    std::thread::sleep(LED_SLEEP_DURATION_MS);
                 }
-                std::thread::sleep(LED_SLEEP_DURATION_MS);
             }
         })?;
 
     espnow_join_handle.join().unwrap();
     osc_receiver_join_handle.join().unwrap();
     osc_sender_join_handle.join().unwrap();
+    #[cfg(feature = "midi")]
+    midi_join_handle.join().unwrap();
+    #[cfg(any(feature = "artnet", feature = "sacn"))]
+    dmx_join_handle.join().unwrap();
     led_join_handle.join().unwrap();
     led1_join_handle.join().unwrap();
 
     info!("Finish app");
     Ok(())
 }
-
-fn eth_configure<'d, T>(
-    sysloop: &EspSystemEventLoop,
-    eth: &mut esp_idf_svc::eth::EspEth<'d, T>,
-) -> Result<Ipv4Addr> {
-    info!("Eth created");
-    let mut eth = esp_idf_svc::eth::BlockingEth::wrap(eth, sysloop.clone())?;
-    eth.start()?;
-
-    info!("Waiting for netif up...");
-
-    eth.wait_netif_up()?;
-
-    let ip_info = eth.eth().netif().get_ip_info()?;
-
-    info!("Eth info: {:?}", ip_info);
-    Ok(ip_info.ip)
-}
\ No newline at end of file
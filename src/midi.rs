@@ -0,0 +1,454 @@
+use anyhow::Result;
+use log::*;
+
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "midi-serial")]
+use esp_idf_hal::{delay::NON_BLOCK, uart::UartDriver};
+#[cfg(feature = "midi-serial")]
+use std::os::fd::RawFd;
+
+use crate::osc::Msg;
+use crate::bus::{self, Event, Overflow, Priority, Subscriber};
+use crate::poll;
+
+// Longest wait for RTP-MIDI or serial input, bounds the latency of node replies sent back as MIDI
+const MIDI_REPLY_TIMEOUT: Duration = Duration::from_millis(10);
+// Bridge sleeps after an error, so it doesn't spin
+const MIDI_ERROR_INTERVAL_MS: Duration = Duration::from_millis(10);
+// Queued node replies for the MIDI thread
+const MIDI_EVENT_CAPACITY: usize = 16;
+pub const MIDI_RTP_PORT_DEFAULT: u16 = 5004;
+pub const MIDI_SERIAL_BAUD_DEFAULT: u32 = 31_250;
+
+// MIDI to ESP-NOW mapping. Channels are 0-15.
+// Note on -> Msg::Run to the device: (channel, note, device no)
+const NOTE_MAP: [(u8, u8, u8); 2] = [(0, 60, 1), (0, 62, 2)];
+// Control change -> Msg::Param to the device: (channel, controller, device no, parameter no)
+const CC_MAP: [(u8, u8, u8, u8); 2] = [(0, 1, 1, 0), (0, 2, 2, 0)];
+// Turn node replies back into MIDI: /boot as note on of the device's note, /status as its controller
+const MIDI_REPLIES: bool = true;
+
+const SESSION_NAME: &str = "ESPNOW OSC Station";
+const RTP_MIDI_PAYLOAD_TYPE: u8 = 0x61;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+}
+
+impl MidiMessage {
+    fn to_bytes(self) -> [u8; 3] {
+        match self {
+            MidiMessage::NoteOn { channel, note, velocity } => [0x90 | channel, note, velocity],
+            MidiMessage::NoteOff { channel, note } => [0x80 | channel, note, 0],
+            MidiMessage::ControlChange { channel, controller, value } => [0xB0 | channel, controller, value],
+        }
+    }
+}
+
+/**
+ * Number of data bytes following a status byte
+*/
+fn data_len(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xEF | 0xF2 => 2,
+        _ => 0,
+    }
+}
+
+/**
+ * MIDI byte stream parser with running status.
+ * Used for serial MIDI and for RTP-MIDI command lists.
+*/
+#[derive(Default)]
+pub struct MidiParser {
+    status: u8,
+    data: [u8; 2],
+    len: usize,
+}
+
+impl MidiParser {
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        // Realtime messages can appear anywhere without breaking running status
+        if byte >= 0xF8 {
+            return None;
+        }
+        if byte & 0x80 != 0 {
+            // System common messages cancel running status
+            self.status = if byte < 0xF0 { byte } else { 0 };
+            self.len = 0;
+            return None;
+        }
+        if self.status == 0 {
+            return None;
+        }
+
+        self.data[self.len] = byte;
+        self.len += 1;
+        if self.len < data_len(self.status) {
+            return None;
+        }
+        self.len = 0;
+
+        let channel = self.status & 0x0F;
+        match self.status & 0xF0 {
+            0x90 if self.data[1] > 0 => Some(MidiMessage::NoteOn { channel, note: self.data[0], velocity: self.data[1] }),
+            0x80 | 0x90 => Some(MidiMessage::NoteOff { channel, note: self.data[0] }),
+            0xB0 => Some(MidiMessage::ControlChange { channel, controller: self.data[0], value: self.data[1] }),
+            _ => None,
+        }
+    }
+
+    /**
+     * Parse RTP-MIDI command list (RFC 6295). Delta times are skipped.
+    */
+    fn parse_command_list(&mut self, list: &[u8], first_has_delta: bool, out: &mut Vec<MidiMessage>) {
+        let mut i = 0;
+        let mut first = true;
+        while i < list.len() {
+            if first_has_delta || !first {
+                // Variable length delta time, max 4 bytes
+                let mut n = 0;
+                while i < list.len() && list[i] & 0x80 != 0 && n < 3 {
+                    i += 1;
+                    n += 1;
+                }
+                i += 1;
+            }
+            first = false;
+            if i >= list.len() {
+                break;
+            }
+
+            let status = list[i];
+            if status == 0xF0 {
+                // Skip SysEx
+                while i < list.len() && list[i] != 0xF7 {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            if status & 0x80 != 0 {
+                self.push(status);
+                i += 1;
+            }
+            let len = if status & 0x80 != 0 { data_len(status) } else { data_len(self.status) };
+            if len == 0 && status & 0x80 == 0 {
+                // Data byte without running status, list is broken
+                break;
+            }
+            for _ in 0..len {
+                if i >= list.len() {
+                    break;
+                }
+                if let Some(msg) = self.push(list[i]) {
+                    out.push(msg);
+                }
+                i += 1;
+            }
+        }
+    }
+}
+
+/**
+ * MIDI bridge. Listens to RTP-MIDI (AppleMIDI session) on the Ethernet interface,
+ * optionally serial MIDI on UART, and converts mapped messages into ESP-NOW frames.
+*/
+pub struct MidiBridge {
+    control_sock: UdpSocket,
+    data_sock: UdpSocket,
+    buf: [u8; 512],
+    ssrc: u32,
+    session: Option<SocketAddr>,
+    out_seq: u16,
+    start: Instant,
+    rtp_parser: MidiParser,
+    #[cfg(feature = "midi-serial")]
+    uart: UartDriver<'static>,
+    // VFS device of the UART, only polled, reads go through the driver
+    #[cfg(feature = "midi-serial")]
+    uart_fd: RawFd,
+    #[cfg(feature = "midi-serial")]
+    serial_parser: MidiParser,
+    events: Subscriber,
+}
+
+impl MidiBridge {
+    /**
+     * Node replies, decoded by OscSender
+    */
+    pub fn subscribe() -> Subscriber {
+        bus::subscribe("midi", MIDI_EVENT_CAPACITY, Overflow::DropOldest, |e| matches!(e, Event::Reply(_)))
+    }
+
+    pub fn new(
+        ip: embedded_svc::ipv4::Ipv4Addr,
+        rtp_port: u16,
+        events: Subscriber,
+        #[cfg(feature = "midi-serial")] uart: UartDriver<'static>,
+    ) -> Self {
+        // AppleMIDI uses a pair of ports: control and control + 1 for data
+        let control_addr = SocketAddrV4::new(ip, rtp_port);
+        let data_addr = SocketAddrV4::new(ip, rtp_port + 1);
+        let control_sock = UdpSocket::bind(control_addr).unwrap();
+        let data_sock = UdpSocket::bind(data_addr).unwrap();
+        // Thread waits in poll on both ports and the UART, then reads whatever arrived
+        control_sock.set_nonblocking(true).unwrap();
+        data_sock.set_nonblocking(true).unwrap();
+
+        info!("RTP-MIDI listening to {control_addr}");
+
+        #[cfg(feature = "midi-serial")]
+        let uart_fd = {
+            let port = uart.port();
+            unsafe { esp_idf_sys::esp_vfs_dev_uart_use_driver(port as _) };
+            let path = std::ffi::CString::new(format!("/dev/uart/{port}")).unwrap();
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK) };
+            if fd < 0 {
+                panic!("MIDI: failed to open {:?}: {}", path, std::io::Error::last_os_error());
+            }
+            fd
+        };
+
+        Self {
+            control_sock,
+            data_sock,
+            buf: [0u8; 512],
+            ssrc: unsafe { esp_idf_sys::esp_random() },
+            session: None,
+            out_seq: 0,
+            start: Instant::now(),
+            rtp_parser: MidiParser::default(),
+            #[cfg(feature = "midi-serial")]
+            uart,
+            #[cfg(feature = "midi-serial")]
+            uart_fd,
+            #[cfg(feature = "midi-serial")]
+            serial_parser: MidiParser::default(),
+            events,
+        }
+    }
+
+    /**
+     * Wait for RTP-MIDI / serial MIDI, handle it and send node replies back as MIDI
+    */
+    pub fn run(&mut self) -> Result<()> {
+        let mut messages = vec![];
+
+        #[cfg(not(feature = "midi-serial"))]
+        let [data_ready, control_ready] = poll::wait_readable([self.data_sock.as_raw_fd(), self.control_sock.as_raw_fd()], MIDI_REPLY_TIMEOUT)?;
+        #[cfg(feature = "midi-serial")]
+        let [data_ready, control_ready, serial_ready] = poll::wait_readable([self.data_sock.as_raw_fd(), self.control_sock.as_raw_fd(), self.uart_fd], MIDI_REPLY_TIMEOUT)?;
+
+        if data_ready {
+            while let Ok((size, addr)) = self.data_sock.recv_from(&mut self.buf) {
+                if size >= 2 && self.buf[0] == 0xFF && self.buf[1] == 0xFF {
+                    self.handle_control(size, addr, true);
+                }
+                else {
+                    self.handle_rtp(size, &mut messages);
+                }
+            }
+        }
+        if control_ready {
+            while let Ok((size, addr)) = self.control_sock.recv_from(&mut self.buf) {
+                self.handle_control(size, addr, false);
+            }
+        }
+
+        #[cfg(feature = "midi-serial")]
+        if serial_ready {
+            let mut serial_buf = [0u8; 32];
+            while let Ok(size @ 1..) = self.uart.read(&mut serial_buf, NON_BLOCK) {
+                for byte in serial_buf[..size].iter() {
+                    if let Some(msg) = self.serial_parser.push(*byte) {
+                        messages.push(msg);
+                    }
+                }
+            }
+        }
+
+        for msg in messages {
+            info!("MIDI: {:?}", msg);
+            self.dispatch(msg);
+        }
+
+        self.check_replies()
+    }
+
+    /**
+     * AppleMIDI session control: invitation, clock sync and end of session
+    */
+    fn handle_control(&mut self, size: usize, addr: SocketAddr, data_port: bool) {
+        if size < 8 || self.buf[0] != 0xFF || self.buf[1] != 0xFF {
+            return;
+        }
+        let sock = if data_port { &self.data_sock } else { &self.control_sock };
+
+        match &self.buf[2..4] {
+            b"IN" if size >= 16 => {
+                info!("RTP-MIDI invitation from {addr}");
+                let mut reply = vec![0xFF, 0xFF, b'O', b'K', 0, 0, 0, 2];
+                // Initiator token
+                reply.extend_from_slice(&self.buf[8..12]);
+                reply.extend_from_slice(&self.ssrc.to_be_bytes());
+                reply.extend_from_slice(SESSION_NAME.as_bytes());
+                reply.push(0);
+                if let Err(e) = sock.send_to(&reply, addr) {
+                    error!("RTP-MIDI: failed to accept invitation: {e}");
+                }
+                if data_port {
+                    self.session = Some(addr);
+                }
+            }
+
+            b"CK" if size >= 36 => {
+                // Reply to the first sync packet with our timestamp, the initiator finishes with count 2
+                if self.buf[8] == 0 {
+                    let mut reply = [0u8; 36];
+                    reply[..4].copy_from_slice(&self.buf[..4]);
+                    reply[4..8].copy_from_slice(&self.ssrc.to_be_bytes());
+                    reply[8] = 1;
+                    reply[12..20].copy_from_slice(&self.buf[12..20]);
+                    reply[20..28].copy_from_slice(&self.timestamp().to_be_bytes());
+                    if let Err(e) = sock.send_to(&reply, addr) {
+                        error!("RTP-MIDI: failed to send sync: {e}");
+                    }
+                }
+            }
+
+            b"BY" => {
+                info!("RTP-MIDI session ended by {addr}");
+                self.session = None;
+            }
+
+            _ => {}
+        }
+    }
+
+    /**
+     * Extract MIDI messages from RTP packet
+    */
+    fn handle_rtp(&mut self, size: usize, messages: &mut Vec<MidiMessage>) {
+        if size < 13 || self.buf[0] & 0xC0 != 0x80 {
+            return;
+        }
+        let header = self.buf[12];
+        let (len, start) = if header & 0x80 != 0 {
+            if size < 14 {
+                return;
+            }
+            ((((header & 0x0F) as usize) << 8) | self.buf[13] as usize, 14)
+        }
+        else {
+            ((header & 0x0F) as usize, 13)
+        };
+        let end = (start + len).min(size);
+        let first_has_delta = header & 0x20 != 0;
+        self.rtp_parser.parse_command_list(&self.buf[start..end], first_has_delta, messages);
+    }
+
+    fn dispatch(&mut self, msg: MidiMessage) {
+        match msg {
+            MidiMessage::NoteOn { channel, note, .. } => {
+                for (ch, n, device_no) in NOTE_MAP {
+                    if ch == channel && n == note {
+                        self.send_downstream_buffer(Msg::Run, &[device_no]);
+                    }
+                }
+            }
+            MidiMessage::ControlChange { channel, controller, value } => {
+                for (ch, cc, device_no, param_no) in CC_MAP {
+                    if ch == channel && cc == controller {
+                        self.send_downstream_buffer(Msg::Param, &[device_no, param_no, value]);
+                    }
+                }
+            }
+            MidiMessage::NoteOff { .. } => {}
+        }
+    }
+
+    fn send_downstream_buffer(&mut self, header: Msg, content: &[u8]){
+        let mut msg_buf = vec![header as u8];
+        msg_buf.extend_from_slice(content);
+
+        bus::publish(Event::Downstream(msg_buf, Priority::Normal));
+    }
+
+    /**
+     * Node replies published by OscSender, converted into MIDI when mapped
+    */
+    fn check_replies(&mut self) -> Result<()> {
+        while let Some(event) = self.events.try_recv() {
+            let frame = match event {
+                Event::Reply(frame) => frame,
+                _ => continue,
+            };
+            if !MIDI_REPLIES || frame.len() < 2 {
+                continue;
+            }
+            if let Some(msg) = reply_to_midi(&frame) {
+                self.send_midi(msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_midi(&mut self, msg: MidiMessage) -> Result<()> {
+        let bytes = msg.to_bytes();
+
+        if let Some(addr) = self.session {
+            let mut packet = vec![0x80, RTP_MIDI_PAYLOAD_TYPE];
+            packet.extend_from_slice(&self.out_seq.to_be_bytes());
+            packet.extend_from_slice(&(self.timestamp() as u32).to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+            // Short command section header without journal and delta time
+            packet.push(bytes.len() as u8);
+            packet.extend_from_slice(&bytes);
+            self.out_seq = self.out_seq.wrapping_add(1);
+            self.data_sock.send_to(&packet, addr)?;
+        }
+
+        #[cfg(feature = "midi-serial")]
+        self.uart.write(&bytes)?;
+
+        Ok(())
+    }
+
+    /**
+     * AppleMIDI timestamp in 100us units
+    */
+    fn timestamp(&self) -> u64 {
+        (self.start.elapsed().as_micros() / 100) as u64
+    }
+
+    /**
+     * run waits in poll, only sleep after an error
+    */
+    pub fn idle(&self) {
+        std::thread::sleep(MIDI_ERROR_INTERVAL_MS);
+    }
+}
+
+/**
+ * Reverse mapping of node replies: [header, device no, payload...]
+*/
+fn reply_to_midi(frame: &[u8]) -> Option<MidiMessage> {
+    let device_no = frame[1];
+    match num::FromPrimitive::from_u8(frame[0]) {
+        Some(Msg::Boot) => NOTE_MAP.iter()
+            .find(|(_, _, no)| *no == device_no)
+            .map(|(channel, note, _)| MidiMessage::NoteOn { channel: *channel, note: *note, velocity: 127 }),
+        Some(Msg::Status) if frame.len() > 2 => CC_MAP.iter()
+            .find(|(_, _, no, _)| *no == device_no)
+            .map(|(channel, controller, _, _)| MidiMessage::ControlChange { channel: *channel, controller: *controller, value: frame[2] & 0x7F }),
+        _ => None,
+    }
+}
@@ -0,0 +1,377 @@
+use anyhow::{bail, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use esp_idf_svc::log::EspLogger;
+
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bus::{self, ConfigChange, Event, Overflow, Subscriber};
+use crate::config::Config;
+use crate::discovery;
+
+// Records waiting for the forwarder thread, more are dropped
+const LOG_QUEUE_CAPACITY: usize = 32;
+// Longest forwarded message, the rest is cut
+const LOG_MESSAGE_MAX_LEN: usize = 200;
+// Forwarded records per second, and the burst allowed above it
+const LOG_RATE_PER_S: u32 = 20;
+const LOG_BURST: u32 = 20;
+// Forwarder wakes up at least this often to pick up destination changes
+const LOG_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+const LOG_DEST_EVENT_CAPACITY: usize = 2;
+
+const SYSLOG_PORT_DEFAULT: u16 = 514;
+// local0
+const SYSLOG_FACILITY: u8 = 16;
+const SYSLOG_APP_NAME: &str = "espnow-osc-station";
+// Syslog hostname is resolved again at this interval, and retried at the shorter one while it never resolved
+const SYSLOG_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+const SYSLOG_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Modules whose level can be set at runtime, "main" is the crate root
+pub const LOG_MODULES: [&str; 20] = [
+    "main", "osc", "espnow", "protocol", "config", "auth", "signature", "bus",
+    "scheduler", "estop", "liveness", "stats", "netlog", "capture", "web", "network", "discovery",
+    "radio", "midi", "dmx",
+];
+const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+// Level of each module, LevelFilter as usize, LEVEL_DEFAULT follows the build's log level
+const LEVEL_DEFAULT: usize = usize::MAX;
+#[allow(clippy::declare_interior_mutable_const)]
+const MODULE_LEVEL_INIT: AtomicUsize = AtomicUsize::new(LEVEL_DEFAULT);
+static MODULE_LEVELS: [AtomicUsize; LOG_MODULES.len()] = [MODULE_LEVEL_INIT; LOG_MODULES.len()];
+
+static ESP_LOGGER: EspLogger = EspLogger;
+static NET_LOGGER: NetLogger = NetLogger;
+
+// Forwarded level, LevelFilter as usize, Off until the forwarder starts
+static FORWARD_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+static QUEUE: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+static QUEUE_COND: Condvar = Condvar::new();
+// Records not forwarded because of the rate limit or a full queue
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static RATE: Mutex<Option<(Instant, u32)>> = Mutex::new(None);
+
+struct LogRecord {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+fn forward_level() -> LevelFilter {
+    level_filter(FORWARD_LEVEL.load(Ordering::Relaxed))
+}
+
+fn level_filter(level: usize) -> LevelFilter {
+    match level {
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => LevelFilter::Off,
+    }
+}
+
+/**
+ * Token bucket, never blocks: a record is dropped when another thread holds the bucket
+*/
+fn take_token() -> bool {
+    let mut rate = match RATE.try_lock() {
+        Ok(rate) => rate,
+        Err(_) => return false,
+    };
+    let now = Instant::now();
+    let (refilled, tokens) = rate.get_or_insert((now, LOG_BURST));
+    let refill = (now.duration_since(*refilled).as_millis() as u32).saturating_mul(LOG_RATE_PER_S) / 1000;
+    if refill > 0 {
+        *tokens = (*tokens + refill).min(LOG_BURST);
+        *refilled = now;
+    }
+    if *tokens == 0 {
+        return false;
+    }
+    *tokens -= 1;
+    true
+}
+
+/**
+ * Logs to the UART through EspLogger, and queues records up to the forward level for the network
+*/
+struct NetLogger;
+
+impl Log for NetLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if let Some(level) = module_level(metadata.target()) {
+            return metadata.level() <= level;
+        }
+        ESP_LOGGER.enabled(metadata) || metadata.level() <= forward_level()
+    }
+
+    fn log(&self, record: &Record) {
+        // Filtered here, so a quiet module doesn't even format its messages
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        ESP_LOGGER.log(record);
+        if record.level() > forward_level() {
+            return;
+        }
+        if !take_token() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut message = record.args().to_string();
+        if message.len() > LOG_MESSAGE_MAX_LEN {
+            let mut end = LOG_MESSAGE_MAX_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        // Logging here would end up in the queue again, count drops silently
+        let mut queue = match QUEUE.try_lock() {
+            Ok(queue) => queue,
+            Err(_) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        if queue.len() >= LOG_QUEUE_CAPACITY {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(LogRecord { level: record.level(), target: record.target().to_string(), message });
+        QUEUE_COND.notify_one();
+    }
+
+    fn flush(&self) {}
+}
+
+/**
+ * Install the logger in place of EspLogger::initialize_default, nothing is forwarded until a Forwarder starts
+*/
+pub fn initialize() {
+    log::set_logger(&NET_LOGGER).unwrap();
+    update_max_level();
+}
+
+fn module_index(module: &str) -> Option<usize> {
+    LOG_MODULES.iter().position(|m| *m == module)
+}
+
+fn target(module: &str) -> String {
+    if module == "main" {
+        CRATE_NAME.to_string()
+    } else {
+        format!("{CRATE_NAME}::{module}")
+    }
+}
+
+/**
+ * Level set at runtime for the module of the log target, None follows the default
+*/
+fn module_level(target: &str) -> Option<LevelFilter> {
+    let path = target.strip_prefix(CRATE_NAME)?;
+    let module = match path.strip_prefix("::") {
+        Some(path) => path.split("::").next().unwrap_or(""),
+        None if path.is_empty() => "main",
+        None => return None,
+    };
+    match MODULE_LEVELS[module_index(module)?].load(Ordering::Relaxed) {
+        LEVEL_DEFAULT => None,
+        level => Some(level_filter(level)),
+    }
+}
+
+fn update_max_level() {
+    let mut max = ESP_LOGGER.get_max_level().max(forward_level());
+    for level in MODULE_LEVELS.iter().map(|l| l.load(Ordering::Relaxed)).filter(|l| *l != LEVEL_DEFAULT) {
+        max = max.max(level_filter(level));
+    }
+    log::set_max_level(max);
+}
+
+/**
+ * Set level of the module, None goes back to the default. Levels above the build's log level are not printed to the UART.
+*/
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) -> Result<()> {
+    let index = match module_index(module) {
+        Some(index) => index,
+        None => bail!("Unknown log module {module}"),
+    };
+    MODULE_LEVELS[index].store(level.map_or(LEVEL_DEFAULT, |l| l as usize), Ordering::Relaxed);
+    ESP_LOGGER.set_target_level(target(module), level.unwrap_or_else(|| ESP_LOGGER.get_max_level()));
+    update_max_level();
+    Ok(())
+}
+
+/**
+ * Apply module levels persisted in NVS
+*/
+pub fn load_levels(config: &Config) {
+    for module in LOG_MODULES.iter() {
+        if let Some(level) = config.log_level(module) {
+            let _ = set_module_level(module, Some(level_filter(level as usize)));
+        }
+    }
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    match level.trim() {
+        "off" => Ok(LevelFilter::Off),
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" => Ok(LevelFilter::Trace),
+        _ => bail!("Invalid log level {level}"),
+    }
+}
+
+enum Target {
+    // /log [level] [target] [message] to the OSC destination, follows /setdestip
+    Osc(SocketAddrV4),
+    // RFC 5424 over UDP, host is an IPv4 address or a hostname resolved by the forwarder thread
+    Syslog { host: String, dest: SocketAddrV4, last_resolve: Option<Instant> },
+}
+
+/**
+ * Sends queued records from its own thread and socket, so logging never waits for the network
+*/
+pub struct Forwarder {
+    sock: UdpSocket,
+    target: Target,
+    hostname: String,
+    dest_events: Subscriber,
+}
+
+impl Forwarder {
+    /**
+     * Destination changes for OSC forwarding, subscribe before the threads start
+    */
+    pub fn subscribe() -> Subscriber {
+        bus::subscribe("log", LOG_DEST_EVENT_CAPACITY, Overflow::DropOldest, |e| matches!(e, Event::ConfigChange(ConfigChange::DestIp(_))))
+    }
+
+    /**
+     * forward: "osc", "syslog:<ip>" or "syslog:<ip>:<port>", ip can be a .local hostname, level: "error", "warn"...
+     * A hostname is not resolved here, so a missing host doesn't stop the boot. Nothing is sent until the forwarder thread resolves it.
+    */
+    pub fn new(forward: &str, level: &str, local_ip: Ipv4Addr, osc_dest: SocketAddrV4, dest_events: Subscriber) -> Result<Self> {
+        let target = match forward.trim() {
+            "osc" => Target::Osc(osc_dest),
+            f => match f.strip_prefix("syslog:") {
+                Some(addr) => {
+                    let (ip, port) = match addr.split_once(':') {
+                        Some((ip, port)) => (ip, port.parse::<u16>()?),
+                        None => (addr, SYSLOG_PORT_DEFAULT),
+                    };
+                    let dest = SocketAddrV4::new(ip.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED), port);
+                    Target::Syslog { host: ip.to_string(), dest, last_resolve: None }
+                }
+                None => bail!("Invalid log forward {forward}"),
+            },
+        };
+        let level = parse_level(level)?;
+        let sock = UdpSocket::bind(SocketAddrV4::new(local_ip, 0))?;
+
+        FORWARD_LEVEL.store(level as usize, Ordering::Relaxed);
+        update_max_level();
+
+        Ok(Self { sock, target, hostname: local_ip.to_string(), dest_events })
+    }
+
+    /**
+     * Send queued records, report records dropped since the last round
+    */
+    pub fn run(&mut self) -> Result<()> {
+        while let Some(event) = self.dest_events.try_recv() {
+            if let (Event::ConfigChange(ConfigChange::DestIp(ip)), Target::Osc(dest)) = (event, &mut self.target) {
+                dest.set_ip(ip);
+            }
+        }
+        self.resolve_syslog();
+
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            self.send(Level::Warn, module_path!(), &format!("{dropped} log records dropped"))?;
+        }
+
+        loop {
+            // Don't hold the queue while sending
+            let record = QUEUE.lock().unwrap().pop_front();
+            match record {
+                Some(record) => self.send(record.level, &record.target, &record.message)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Resolve the syslog hostname when due, the previous address is kept when it doesn't resolve
+    */
+    fn resolve_syslog(&mut self) {
+        if let Target::Syslog { host, dest, last_resolve } = &mut self.target {
+            let interval = if dest.ip().is_unspecified() { SYSLOG_RETRY_INTERVAL } else { SYSLOG_RESOLVE_INTERVAL };
+            if host.parse::<Ipv4Addr>().is_ok() || last_resolve.map_or(false, |t| t.elapsed() < interval) {
+                return;
+            }
+            *last_resolve = Some(Instant::now());
+            match discovery::resolve(host) {
+                Ok(ip) => dest.set_ip(ip),
+                // Host may just not be up yet, retried at the interval
+                Err(e) => log::info!("Syslog host {host} not resolved: {e}"),
+            }
+        }
+    }
+
+    fn send(&mut self, level: Level, target: &str, message: &str) -> Result<()> {
+        let (buf, dest) = match self.target {
+            Target::Osc(dest) => {
+                let buf = rosc::encoder::encode(&OscPacket::Message(OscMessage {
+                    addr: "/log".to_string(),
+                    args: vec![
+                        OscType::String(level.to_string().to_lowercase()),
+                        OscType::String(target.to_string()),
+                        OscType::String(message.to_string()),
+                    ],
+                }))?;
+                (buf, dest)
+            }
+            Target::Syslog { dest, .. } => {
+                let severity = match level {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                // No clock, timestamp is nil. MSGID is the module, up to 32 characters.
+                let msgid: String = target.chars().take(32).collect();
+                let line = format!("<{}>1 - {} {} - {} - {}", SYSLOG_FACILITY * 8 + severity, self.hostname, SYSLOG_APP_NAME, msgid, message);
+                (line.into_bytes(), dest)
+            }
+        };
+        // Destination hostname not resolved yet
+        if dest.ip().is_unspecified() {
+            return Ok(());
+        }
+        // A failed send must not be logged, it would be forwarded again
+        let _ = self.sock.send_to(&buf, dest);
+        Ok(())
+    }
+
+    /**
+     * Block until a record is queued or the idle timeout
+    */
+    pub fn idle(&self) {
+        let queue = QUEUE.lock().unwrap();
+        let _ = QUEUE_COND.wait_timeout_while(queue, LOG_IDLE_TIMEOUT, |q| q.is_empty()).unwrap();
+    }
+}
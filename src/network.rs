@@ -0,0 +1,256 @@
+use anyhow::Result;
+use log::*;
+use num_derive::FromPrimitive;
+
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::eth::{BlockingEth, EspEth};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use embedded_svc::ipv4::{self, ClientConfiguration, ClientSettings, Mask, Subnet};
+use embedded_svc::wifi::{self as wifi, AccessPointConfiguration, AuthMethod};
+
+use crate::config::Config;
+use crate::radio;
+
+pub const DHCP_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+// WiFi station fallback starts when the Ethernet link is not up in this time, unless ETH_LINK_TIMEOUT_MS is set
+pub const ETH_LINK_TIMEOUT_DEFAULT: Duration = Duration::from_secs(10);
+const DEFAULT_PREFIX: u8 = 24;
+// Lease is polled, the DHCP client has no blocking wait
+const IP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// DHCP without fallback keeps waiting, reported at this interval
+const DHCP_WAIT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/*
+ * Settings persisted in NVS
+ * [mode, ip (4 bytes), prefix, gateway (4 bytes), dns (4 bytes, 0.0.0.0 none), DHCP timeout ms (u32 LE)]
+*/
+pub const IP_SETTINGS_LEN: usize = 18;
+
+#[derive(FromPrimitive, Clone, Copy, Debug, PartialEq)]
+pub enum IpMode {
+    Static = 0,
+    Dhcp = 1,
+    // DHCP, static address when no lease in the timeout
+    DhcpFallback = 2,
+}
+
+impl IpMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "static" => Some(IpMode::Static),
+            "dhcp" => Some(IpMode::Dhcp),
+            "fallback" => Some(IpMode::DhcpFallback),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpMode::Static => "static",
+            IpMode::Dhcp => "dhcp",
+            IpMode::DhcpFallback => "fallback",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IpSettings {
+    pub mode: IpMode,
+    pub ip: Ipv4Addr,
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+    pub dhcp_timeout: Duration,
+}
+
+impl IpSettings {
+    /**
+     * Settings from NVS, the build's static address when there are none
+    */
+    pub fn load(config: &Config, default_ip: Ipv4Addr, default_gateway: Ipv4Addr) -> Self {
+        let default = Self {
+            mode: IpMode::Static,
+            ip: default_ip,
+            prefix: DEFAULT_PREFIX,
+            gateway: default_gateway,
+            dns: None,
+            dhcp_timeout: DHCP_TIMEOUT_DEFAULT,
+        };
+        match config.ip_settings().and_then(|b| Self::from_bytes(&b)) {
+            Some(settings) => settings,
+            None => default,
+        }
+    }
+
+    pub fn from_bytes(b: &[u8; IP_SETTINGS_LEN]) -> Option<Self> {
+        let mode = num::FromPrimitive::from_u8(b[0])?;
+        let ip = |i: usize| Ipv4Addr::new(b[i], b[i + 1], b[i + 2], b[i + 3]);
+        if b[5] > 32 {
+            return None;
+        }
+        let dns = Some(ip(10)).filter(|dns| !dns.is_unspecified());
+        let timeout_ms = u32::from_le_bytes([b[14], b[15], b[16], b[17]]);
+        Some(Self {
+            mode,
+            ip: ip(1),
+            prefix: b[5],
+            gateway: ip(6),
+            dns,
+            dhcp_timeout: Duration::from_millis(timeout_ms as u64),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; IP_SETTINGS_LEN] {
+        let mut b = [0u8; IP_SETTINGS_LEN];
+        b[0] = self.mode as u8;
+        b[1..5].copy_from_slice(&self.ip.octets());
+        b[5] = self.prefix;
+        b[6..10].copy_from_slice(&self.gateway.octets());
+        b[10..14].copy_from_slice(&self.dns.unwrap_or(Ipv4Addr::UNSPECIFIED).octets());
+        b[14..18].copy_from_slice(&(self.dhcp_timeout.as_millis() as u32).to_le_bytes());
+        b
+    }
+
+    /**
+     * Ethernet netif with the static address, or the DHCP client
+    */
+    fn netif(&self, dhcp: bool) -> Result<EspNetif> {
+        let mut conf = NetifConfiguration::eth_default_client();
+        if !dhcp {
+            conf.ip_configuration = ipv4::Configuration::Client(ClientConfiguration::Fixed(ClientSettings {
+                ip: self.ip,
+                subnet: Subnet {
+                    gateway: self.gateway,
+                    mask: Mask(self.prefix),
+                },
+                dns: self.dns,
+                secondary_dns: None,
+            }));
+        }
+        Ok(EspNetif::new_with_conf(&conf)?)
+    }
+}
+
+/**
+ * Create the Ethernet netif for the settings, wrapped with the driver by the caller
+*/
+pub fn eth_netif(settings: &IpSettings) -> Result<EspNetif> {
+    settings.netif(settings.mode != IpMode::Static)
+}
+
+/**
+ * Start Ethernet and wait for the address. Returns the address and the mode in effect:
+ * DhcpFallback only when the static address replaced a missing lease.
+ * With link_timeout, Ethernet is stopped and None returned when the link doesn't come up in time.
+*/
+pub fn eth_configure<'d, T>(
+    sysloop: &EspSystemEventLoop,
+    eth: &mut EspEth<'d, T>,
+    settings: &IpSettings,
+    link_timeout: Option<Duration>,
+) -> Result<Option<(Ipv4Addr, IpMode)>> {
+    info!("Eth created, IP mode: {}", settings.mode.as_str());
+    BlockingEth::wrap(&mut *eth, sysloop.clone())?.start()?;
+
+    if let Some(timeout) = link_timeout {
+        if !wait_link(eth, timeout)? {
+            warn!("No Ethernet link in {:?}", timeout);
+            eth.stop()?;
+            return Ok(None);
+        }
+    }
+    if settings.mode == IpMode::Static {
+        info!("Waiting for netif up...");
+        BlockingEth::wrap(&mut *eth, sysloop.clone())?.wait_netif_up()?;
+    }
+
+    let mode = match settings.mode {
+        IpMode::Static => IpMode::Static,
+        IpMode::Dhcp => {
+            while wait_lease(eth, DHCP_WAIT_REPORT_INTERVAL)?.is_none() {
+                warn!("No DHCP lease yet");
+            }
+            IpMode::Dhcp
+        }
+        IpMode::DhcpFallback => {
+            if wait_lease(eth, settings.dhcp_timeout)?.is_some() {
+                IpMode::Dhcp
+            }
+            else {
+                warn!("No DHCP lease in {:?}, falling back to {}/{}", settings.dhcp_timeout, settings.ip, settings.prefix);
+                eth.stop()?;
+                eth.swap_netif(settings.netif(false)?)?;
+                let mut eth = BlockingEth::wrap(&mut *eth, sysloop.clone())?;
+                eth.start()?;
+                eth.wait_netif_up()?;
+                IpMode::DhcpFallback
+            }
+        }
+    };
+
+    let ip_info = eth.netif().get_ip_info()?;
+    info!("Eth info: {:?}", ip_info);
+    info!("IP address {} ({})", ip_info.ip, mode.as_str());
+    Ok(Some((ip_info.ip, mode)))
+}
+
+fn wait_link<T>(eth: &EspEth<'_, T>, timeout: Duration) -> Result<bool> {
+    let start = Instant::now();
+    while !eth.is_connected()? {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        std::thread::sleep(IP_POLL_INTERVAL);
+    }
+    Ok(true)
+}
+
+/**
+ * Join the WPA2 network as station, the AP for ESP-NOW stays up on the network's channel.
+ * Returns the DHCP address and the channel.
+*/
+pub fn wifi_sta_configure(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    ssid: &str,
+    password: &str,
+    ap: AccessPointConfiguration,
+) -> Result<(Ipv4Addr, u8)> {
+    info!("Joining WiFi {ssid} as station");
+    let sta = wifi::ClientConfiguration {
+        ssid: ssid.into(),
+        password: password.into(),
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    };
+    wifi.set_configuration(&wifi::Configuration::Mixed(sta, ap))?;
+    wifi.connect()?;
+    wifi.wait_netif_up()?;
+
+    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    let channel = radio::channel()?;
+
+    info!("WiFi info: {:?}", ip_info);
+    info!("IP address {} (wifi), channel {channel}", ip_info.ip);
+    Ok((ip_info.ip, channel))
+}
+
+/**
+ * Poll for the DHCP lease, None after the timeout
+*/
+fn wait_lease<T>(eth: &EspEth<'_, T>, timeout: Duration) -> Result<Option<Ipv4Addr>> {
+    let start = Instant::now();
+    loop {
+        let ip = eth.netif().get_ip_info()?.ip;
+        if eth.netif().is_up()? && !ip.is_unspecified() {
+            return Ok(Some(ip));
+        }
+        if start.elapsed() >= timeout {
+            return Ok(None);
+        }
+        std::thread::sleep(IP_POLL_INTERVAL);
+    }
+}
@@ -8,44 +8,75 @@ use esp_idf_hal::reset::restart;
 extern crate num;
 extern crate num_derive;
 
-use std::net::{SocketAddrV4, UdpSocket, Ipv4Addr};
-use std::time::Duration;
-
-use bbqueue::framed::{FrameProducer, FrameConsumer};
-
-const OSC_LISTEN_INTERVAL_MS: Duration = Duration::from_millis(1);
-pub const MSG_BUF_DOWNSTREAM: usize = 128;
-pub const MSG_BUF_UPTREAM: usize = 128;
-pub const MSG_BUF_LED: usize = 4;
-pub const MSG_BUF_ERROR: usize = 4;
-pub const MSG_BUF_IP: usize = 32;
+use std::net::{SocketAddrV4, SocketAddr, UdpSocket, Ipv4Addr, IpAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, DuplicateFilter, PROTOCOL_V1, CAP_APP_ACK};
+use crate::config::{Config, KEY_LEN};
+use crate::auth::{Auth, Denied, PRIVILEGED, CONFIG_COMMANDS};
+use crate::signature::SPOOF_COUNT;
+use crate::bus::{self, Event, SendResult, ConfigChange, Indicator, Overflow, Priority, Query, Subscriber, UPSTREAM_LATENCY};
+use crate::liveness::{self, NodeState};
+use crate::scheduler::{self, NODE_QUEUE_CAPACITY};
+use crate::netlog;
+use crate::espnow::NODE_ADDRESSES;
+use crate::capture::{self, Direction};
+use crate::network::{IpMode, IpSettings};
+use crate::radio::{Phy, RadioSettings};
+use crate::stats::{self, OSC_RECEIVED, OSC_DECODED, OSC_FAILED, UPSTREAM_FORWARDED, UPSTREAM_DROPPED, ESPNOW_SENT, ESPNOW_DELIVERED, ESPNOW_FAILED, ESPNOW_RETRIED, ETH_UP};
+
+// Receiver sleeps after a socket error, so it doesn't spin
+const OSC_ERROR_INTERVAL_MS: Duration = Duration::from_millis(10);
+// Sender has no periodic work, wait for queued events
+const OSC_SENDER_IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+// Queued events for the OSC sender
+const OSC_SENDER_EVENT_CAPACITY: usize = 32;
+// Addresses sent as high priority, unless OSC_HIGH_PRIORITY is set
+const HIGH_PRIORITY_DEFAULT: [&str; 1] = ["/reset"];
+// Queues dropping events are reported at most once per interval
+const OVERFLOW_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 #[allow(dead_code)]
 #[derive(FromPrimitive, Clone, Copy)]
 pub enum Msg {
+    Ack = 0x41,             // 'A' 'App level ACK' (seq)
     Boot = 0x42,            // 'B' "Boot report"
+    EstopAck = 0x53,        // 'S' 'e-Stop state ack' (latched)
     Mac = 0x4D,             // 'M' 'MAC report'
     Status = 0x55,          // 'U' 'statUs'
 
     Reset =     0x62,       // 'b' 'Reset'
+    Dmx = 0x64,             // d, DMX channel values
+    EstopRelease = 0x6C,    // l, reLease e-stop
     MacQuery = 0x6D,        // 'm' 'mac address query'
+    Param = 0x70,           // p, Parameter (param no, value)
     Run = 0x72,             // r, Run
+    Estop = 0x73,           // s, emergency Stop
     StatusQuery = 0x75,     // u, statUs
 }
 
 pub struct OscReceiver {
     sock: UdpSocket,
     buf: [u8; rosc::decoder::MTU],
-    sender: FrameProducer<'static, MSG_BUF_DOWNSTREAM>,
-    destip_producer: FrameProducer<'static, MSG_BUF_IP>,
+    config: Arc<Mutex<Config>>,
+    auth: Auth,
+    high_priority: Vec<String>,
+    // Stored IP settings, starting point of /setip
+    ip_settings: IpSettings,
+    // Stored radio settings, starting point of /setradio
+    radio_settings: RadioSettings,
 }
 
 impl OscReceiver {
     pub fn new(
         ip: embedded_svc::ipv4::Ipv4Addr,
         recv_port: u16,
-        sender: FrameProducer<'static, MSG_BUF_DOWNSTREAM>,
-        destip_producer: FrameProducer<'static, MSG_BUF_IP>,
+        config: Arc<Mutex<Config>>,
+        auth: Auth,
+        high_priority: Option<&str>,
+        ip_settings: IpSettings,
+        radio_settings: RadioSettings,
     ) -> Self {
         let recv_addr = SocketAddrV4::new(ip, recv_port);
         let sock = UdpSocket::bind(recv_addr).unwrap();
@@ -53,11 +84,20 @@ impl OscReceiver {
 
         info!("Listening to {recv_addr}");
 
+        // Comma separated OSC addresses
+        let high_priority = match high_priority {
+            Some(list) => list.split(',').map(|a| a.trim().to_string()).filter(|a| !a.is_empty()).collect(),
+            None => HIGH_PRIORITY_DEFAULT.iter().map(|a| a.to_string()).collect(),
+        };
+
         Self {
             sock,
             buf,
-            sender,
-            destip_producer,
+            config,
+            auth,
+            high_priority,
+            ip_settings,
+            radio_settings,
         }
     }
 
@@ -68,15 +108,34 @@ impl OscReceiver {
         match self.sock.recv_from(&mut self.buf) {
             Ok((size, _addr)) => {
                 info!("Received packet with size {size} from: {_addr}");
+                stats::count(&OSC_RECEIVED);
 
                 let res = rosc::decoder::decode_udp(&self.buf[..size]);
+                if let SocketAddr::V4(src) = _addr {
+                    // Keys are not captured
+                    let secret = matches!(&res, Ok((_, OscPacket::Message(m))) if CONFIG_COMMANDS.contains(&m.addr.as_str()));
+                    capture::osc(Direction::In, src, if secret { &[] } else { &self.buf[..size] });
+                }
                 match res {
                     Ok((_, packet)) => {
+                        stats::count(&OSC_DECODED);
                         match packet {
-                            OscPacket::Message(msg) => {
+                            OscPacket::Message(mut msg) => {
                                 info!("OSC address: {}", msg.addr);
                                 info!("OSC arguments: {:?}, len:{}", msg.args, msg.args.len());
 
+                                if PRIVILEGED.contains(&msg.addr.as_str()) {
+                                    let src_ip = match _addr.ip() {
+                                        IpAddr::V4(ip) => ip,
+                                        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+                                    };
+                                    if let Err(reason) = self.auth.check(src_ip, &mut msg) {
+                                        error!("Denied {} from {src_ip}: {}", msg.addr, reason.as_str());
+                                        self.notify_denied(src_ip, reason, &msg.addr);
+                                        return Ok(());
+                                    }
+                                }
+
                                 let device_no = if msg.args.len() > 0 {
                                     match msg.args[0] {
                                         OscType::Int(no) => {
@@ -87,11 +146,12 @@ impl OscReceiver {
                                 }
                                 else {0u8};
                                 info!("devNo: {device_no}");
+                                let priority = self.priority(&msg.addr);
 
                                 match msg.addr.as_str() {
                                     "/macquery" => {
                                         if msg.args.len() == 1 {
-                                            self.send_downstream_buffer(Msg::MacQuery, &[device_no]);
+                                            self.send_downstream_buffer(Msg::MacQuery, &[device_no], priority);
                                         }
                                     }
 
@@ -102,31 +162,159 @@ impl OscReceiver {
                                                 self.reset_sequence();
                                             }
                                             else {
-                                                self.send_downstream_buffer(Msg::Reset, &[device_no]);
+                                                self.send_downstream_buffer(Msg::Reset, &[device_no], priority);
                                             }
                                         }
                                     }
 
+                                    "/estop" => {
+                                        self.notify_estop(true);
+                                    }
+
+                                    "/estop/release" => {
+                                        self.notify_estop(false);
+                                    }
+
+                                    "/nodes" => {
+                                        bus::publish(Event::Query(Query::Nodes));
+                                    }
+
+                                    "/stats" => {
+                                        bus::publish(Event::Query(Query::Stats));
+                                    }
+
                                     "/statusquery" => {
                                         if msg.args.len() == 1 {
-                                            self.send_downstream_buffer(Msg::StatusQuery, &[device_no]);
+                                            liveness::queried(device_no as usize);
+                                            self.send_downstream_buffer(Msg::StatusQuery, &[device_no], priority);
                                         }
                                     }
 
                                     "/run" => {
                                         if msg.args.len() == 1 {
-                                            self.send_downstream_buffer(Msg::Run, &[device_no]);
+                                            self.send_downstream_buffer(Msg::Run, &[device_no], priority);
+                                        }
+                                    }
+
+                                    "/protocol" => {
+                                        // /protocol [device no] [version], when the node's boot message was missed
+                                        if msg.args.len() == 2 {
+                                            if let OscType::Int(version) = msg.args[1] {
+                                                protocol::set_protocol(device_no as usize, version as u8);
+                                            }
+                                        }
+                                    }
+
+                                    "/appack" => {
+                                        // /appack [device no] [0/1], when the node's boot message was missed
+                                        if msg.args.len() == 2 {
+                                            if let OscType::Int(enable) = msg.args[1] {
+                                                let caps = protocol::capabilities(device_no as usize);
+                                                let caps = if enable != 0 { caps | CAP_APP_ACK } else { caps & !CAP_APP_ACK };
+                                                protocol::set_capabilities(device_no as usize, caps);
+                                            }
+                                        }
+                                    }
+
+                                    "/setpmk" => {
+                                        // /setpmk [16 byte key]
+                                        if let Some(pmk) = parse_key(&msg.args) {
+                                            self.config.lock().unwrap().set_pmk(&pmk)?;
+                                            self.notify_peer_config(0);
+                                        }
+                                    }
+
+                                    "/setlmk" => {
+                                        // /setlmk [device no] [16 byte key]
+                                        if device_no != 0 && msg.args.len() > 1 {
+                                            if let Some(lmk) = parse_key(&msg.args[1..]) {
+                                                self.config.lock().unwrap().set_lmk(device_no as usize, &lmk)?;
+                                                self.notify_peer_config(device_no);
+                                            }
+                                        }
+                                    }
+
+                                    "/clearlmk" => {
+                                        // /clearlmk [device no]
+                                        if device_no != 0 && msg.args.len() == 1 {
+                                            self.config.lock().unwrap().clear_lmk(device_no as usize)?;
+                                            self.notify_peer_config(device_no);
+                                        }
+                                    }
+
+                                    "/setsigkey" => {
+                                        // /setsigkey [device no] [16 byte key]
+                                        if device_no != 0 && msg.args.len() > 1 {
+                                            if let Some(key) = parse_key(&msg.args[1..]) {
+                                                self.config.lock().unwrap().set_sig_key(device_no as usize, &key)?;
+                                                self.notify_peer_config(device_no);
+                                            }
+                                        }
+                                    }
+
+                                    "/clearsigkey" => {
+                                        // /clearsigkey [device no]
+                                        if device_no != 0 && msg.args.len() == 1 {
+                                            self.config.lock().unwrap().clear_sig_key(device_no as usize)?;
+                                            self.notify_peer_config(device_no);
+                                        }
+                                    }
+
+                                    "/capture" => {
+                                        // /capture [1 start / 0 stop and clear]
+                                        if let [OscType::Int(enable)] = msg.args[..] {
+                                            capture::enable(enable != 0);
+                                        }
+                                    }
+
+                                    "/capture/stream" => {
+                                        // /capture/stream [ip0] [ip1] [ip2] [ip3] [port], no argument stops
+                                        match msg.args[..] {
+                                            [OscType::Int(a), OscType::Int(b), OscType::Int(c), OscType::Int(d), OscType::Int(port)] => {
+                                                let ip = Ipv4Addr::new(a as u8, b as u8, c as u8, d as u8);
+                                                capture::stream(Some(SocketAddrV4::new(ip, port as u16)));
+                                            }
+                                            [] => capture::stream(None),
+                                            _ => {}
+                                        }
+                                    }
+
+                                    "/loglevel" => {
+                                        // /loglevel [module] [off/error/warn/info/debug/trace/default]
+                                        if let [OscType::String(module), OscType::String(level)] = &msg.args[..] {
+                                            self.set_log_level(module, level)?;
+                                        }
+                                    }
+
+                                    "/setip" => {
+                                        // /setip [static/dhcp/fallback] [ip] [prefix] [gateway] [dns] [DHCP timeout ms]
+                                        self.set_ip_settings(&msg.args)?;
+                                    }
+
+                                    "/setradio" => {
+                                        // /setradio [channel/txpower/phy/country] [value]
+                                        if let [OscType::String(setting), value] = &msg.args[..] {
+                                            self.set_radio_setting(setting, value)?;
                                         }
                                     }
 
                                     "/setdestip" => {
+                                        // /setdestip [ip0] [ip1] [ip2] [ip3], or /setdestip [hostname]
                                         if msg.args.len() == 4 {
-                                            let mut commandbuf = vec![];
-                                            for arg in msg.args.iter(){
-                                                let ip = arg.clone().int().unwrap();
-                                                commandbuf.push((ip & 0xFF) as u8);
+                                            let mut commandbuf = [0u8; 4];
+                                            for (b, arg) in commandbuf.iter_mut().zip(msg.args.iter()){
+                                                *b = match arg {
+                                                    OscType::Int(ip) if (0..=255).contains(ip) => *ip as u8,
+                                                    _ => bail!("Bad /setdestip arguments: {:?}", msg.args),
+                                                };
                                             }
-                                            self.notify_new_destip(&commandbuf);
+                                            self.notify_new_dest(Ipv4Addr::from(commandbuf).to_string());
+                                        }
+                                        else if let [OscType::String(host)] = &msg.args[..] {
+                                            self.notify_new_dest(host.clone());
+                                        }
+                                        else {
+                                            bail!("Bad /setdestip arguments: {:?}", msg.args);
                                         }
                                     }
 
@@ -139,6 +327,7 @@ impl OscReceiver {
                         }
                     }
                     Err(e) => {
+                        stats::count(&OSC_FAILED);
                         bail!("Error receiving OSC msg: {e}");
                     }
                 }
@@ -150,7 +339,16 @@ impl OscReceiver {
         }
     }
 
-    fn send_downstream_buffer(&mut self, header: Msg, content: &[u8]){
+    fn priority(&self, addr: &str) -> Priority {
+        if self.high_priority.iter().any(|a| a == addr) {
+            Priority::High
+        }
+        else {
+            Priority::Normal
+        }
+    }
+
+    fn send_downstream_buffer(&mut self, header: Msg, content: &[u8], priority: Priority){
         let mut msg_buf = vec![];
         msg_buf.push(header as u8);
         for ct in content.iter(){
@@ -158,32 +356,138 @@ impl OscReceiver {
         }
         info!("Downstream buf:{:02X?}", msg_buf);
 
-        let sz = msg_buf.len();
-        if let Ok(mut wg) = self.sender.grant(sz){
-            wg.to_commit(sz);
-            wg.copy_from_slice(msg_buf.as_slice());
-            wg.commit(sz);
-        }
-        else{
-            error!("Downstream Buffer Overflow!");
+        bus::publish(Event::Downstream(msg_buf, priority));
+    }
+
+    /**
+     * Notify new upstream destination, resolved by the destination resolver
+    */
+    fn notify_new_dest(&mut self, host: String){
+        info!("New Dest:{host}");
+        bus::publish(Event::ConfigChange(ConfigChange::DestHost(host)));
+    }
+
+    /**
+     * Report rejected privileged command
+    */
+    fn notify_denied(&mut self, src_ip: Ipv4Addr, reason: Denied, addr: &str){
+        bus::publish(Event::Denied { src: src_ip, reason, addr: addr.to_string() });
+    }
+
+    /**
+     * Set log level of the module now and persist it, "default" goes back to the build's log level
+    */
+    fn set_log_level(&mut self, module: &str, level: &str) -> Result<()> {
+        let level = match level {
+            "default" => None,
+            level => Some(netlog::parse_level(level)?),
+        };
+        netlog::set_module_level(module, level)?;
+        let mut config = self.config.lock().unwrap();
+        match level {
+            Some(level) => config.set_log_level(module, level as u32)?,
+            None => config.clear_log_level(module)?,
         }
+        info!("Log level of {module}: {:?}", level);
+        Ok(())
     }
 
     /**
-     * Notify new upstream IP address
+     * Persist Ethernet IP settings, applied after reset. Omitted arguments keep the stored ones.
     */
-    fn notify_new_destip(&mut self, newip: &[u8]){
-        info!("New Dest IP:{:02X?}", newip);
+    fn set_ip_settings(&mut self, args: &[OscType]) -> Result<()> {
+        let mut settings = self.ip_settings;
+        let mode = match args.first() {
+            Some(OscType::String(mode)) => mode,
+            _ => bail!("/setip without mode"),
+        };
+        settings.mode = match IpMode::parse(mode) {
+            Some(mode) => mode,
+            None => bail!("Unknown IP mode: {mode}"),
+        };
+        let address = |arg: Option<&OscType>| match arg {
+            Some(OscType::String(ip)) => ip.parse::<Ipv4Addr>().map(Some).map_err(|_| anyhow::anyhow!("Bad address: {ip}")),
+            _ => Ok(None),
+        };
+        if let Some(ip) = address(args.get(1))? {
+            settings.ip = ip;
+        }
+        if let Some(OscType::Int(prefix)) = args.get(2) {
+            if !(0..=32).contains(prefix) {
+                bail!("Bad prefix: {prefix}");
+            }
+            settings.prefix = *prefix as u8;
+        }
+        if let Some(gateway) = address(args.get(3))? {
+            settings.gateway = gateway;
+        }
+        match args.get(4) {
+            // Empty string clears the DNS server
+            Some(OscType::String(dns)) if dns.is_empty() => settings.dns = None,
+            arg => {
+                if let Some(dns) = address(arg)? {
+                    settings.dns = Some(dns);
+                }
+            }
+        }
+        if let Some(OscType::Int(timeout)) = args.get(5) {
+            settings.dhcp_timeout = Duration::from_millis(*timeout as u64);
+        }
+        self.config.lock().unwrap().set_ip_settings(&settings.to_bytes())?;
+        self.ip_settings = settings;
+        info!("IP settings stored, applied after reset: {:?}", settings);
+        Ok(())
+    }
 
-        let sz = newip.len();
-        if let Ok(mut wg) = self.destip_producer.grant(sz){
-            wg.to_commit(sz);
-            wg.copy_from_slice(newip);
-            wg.commit(sz);
+    /**
+     * Validate and persist a radio setting. TX power, PHY and country apply now, the channel after reset.
+    */
+    fn set_radio_setting(&mut self, setting: &str, value: &OscType) -> Result<()> {
+        let mut settings = self.radio_settings;
+        match (setting, value) {
+            ("channel", OscType::Int(channel)) => settings.channel = (*channel).clamp(0, u8::MAX as i32) as u8,
+            // dBm, 0 for the driver's default
+            ("txpower", OscType::Int(dbm)) => settings.tx_power = dbm.saturating_mul(4).clamp(0, u8::MAX as i32) as u8,
+            ("txpower", OscType::Float(dbm)) => settings.tx_power = (*dbm * 4.0).clamp(0.0, u8::MAX as f32) as u8,
+            ("phy", OscType::String(phy)) => {
+                settings.phy = match Phy::parse(phy) {
+                    Some(phy) => phy,
+                    None => bail!("Unknown PHY: {phy}"),
+                };
+            }
+            ("country", OscType::String(cc)) => {
+                settings.country = match cc.as_bytes() {
+                    [] => None,
+                    [a, b] => Some([a.to_ascii_uppercase(), b.to_ascii_uppercase()]),
+                    _ => bail!("Bad country code: {cc}"),
+                };
+            }
+            _ => bail!("Bad radio setting: {setting} {:?}", value),
+        }
+        settings.validate()?;
+        self.config.lock().unwrap().set_radio_settings(&settings.to_bytes())?;
+        self.radio_settings = settings;
+        if setting == "channel" {
+            info!("Radio channel {} stored, applied after reset", settings.channel);
         }
-        else{
-            error!("unable to get buffer for dest ip!");
+        else {
+            settings.apply()?;
         }
+        Ok(())
+    }
+
+    /**
+     * Notify ESPNOW thread to latch or release e-stop on every node
+    */
+    fn notify_estop(&mut self, latched: bool){
+        bus::publish(Event::Estop(latched));
+    }
+
+    /**
+     * Notify ESPNOW thread to apply new keys, 0 for PMK
+    */
+    fn notify_peer_config(&mut self, device_no: u8){
+        bus::publish(Event::ConfigChange(ConfigChange::PeerKeys(device_no)));
     }
 
     /**
@@ -196,36 +500,78 @@ impl OscReceiver {
     }
 
     /**
-     * Sleep until next interval
+     * recv_from blocks until the next packet, only sleep after an error
     */
     pub fn idle(&self) {
-        std::thread::sleep(OSC_LISTEN_INTERVAL_MS);
+        std::thread::sleep(OSC_ERROR_INTERVAL_MS);
     }
 
 }
 
+/**
+ * 16 byte key, as a blob or 16 int arguments
+*/
+fn parse_key(args: &[OscType]) -> Option<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    match args {
+        [OscType::Blob(blob)] if blob.len() == KEY_LEN => {
+            key.copy_from_slice(blob);
+        }
+        _ if args.len() == KEY_LEN => {
+            for (k, arg) in key.iter_mut().zip(args.iter()) {
+                match arg {
+                    OscType::Int(v) => *k = *v as u8,
+                    _ => return None,
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(key)
+}
+
 ///////////////////////////////////////////////////////
 // Upstream Messenger
-// ESPNOW Receiver -> Upstream Message Buffer -> OSC Send out
+// ESPNOW Receiver -> Upstream events -> OSC Send out
 pub struct OscSender {
     sock: UdpSocket,
-    consumer: FrameConsumer<'static, MSG_BUF_UPTREAM>,
+    events: Subscriber,
     dest_addr: SocketAddrV4,
-    led_producer: FrameProducer<'static, MSG_BUF_LED>,
-    error_msg_consumer: FrameConsumer<'static, MSG_BUF_ERROR>,
-    destip_consumer: FrameConsumer<'static, MSG_BUF_IP>,
+    duplicate_filter: DuplicateFilter,
+    // Drop count of each queue at the last /overflow report
+    reported_drops: Vec<(&'static str, u32)>,
+    // Same for the ESPNOW scheduler's node queues
+    reported_node_drops: [u32; NODE_ADDRESSES.len()],
+    last_overflow_check: Instant,
+    // Periodic /stats push, None only answers /stats queries
+    stats_interval: Option<Duration>,
+    last_stats: Instant,
 }
 
 impl OscSender {
+    /**
+     * Upstream frames, failed sends, destination changes and security reports
+    */
+    pub fn subscribe() -> Subscriber {
+        bus::subscribe("osc", OSC_SENDER_EVENT_CAPACITY, Overflow::DropOldest, |e| matches!(e,
+            Event::Upstream(_)
+            | Event::SendResult { result: SendResult::Failed, .. }
+            | Event::ConfigChange(ConfigChange::DestIp(_))
+            | Event::Denied { .. }
+            | Event::Spoof(_)
+            | Event::EstopAck(_)
+            | Event::EstopMissing(_)
+            | Event::NodeState { .. }
+            | Event::Query(_)))
+    }
+
     pub fn new(
         dest_ip: embedded_svc::ipv4::Ipv4Addr,
         dest_port: u16,
         host_ip: embedded_svc::ipv4::Ipv4Addr,
         host_port: u16,
-        consumer: FrameConsumer<'static, MSG_BUF_UPTREAM>,
-        led_producer: FrameProducer<'static, MSG_BUF_LED>,
-        error_msg_consumer: FrameConsumer<'static, MSG_BUF_ERROR>,
-        destip_consumer: FrameConsumer<'static, MSG_BUF_IP>,
+        events: Subscriber,
+        stats_interval: Option<Duration>,
     ) -> Self {
         let dest_addr = SocketAddrV4::new(dest_ip, dest_port);
         let host_addr = SocketAddrV4::new(host_ip, host_port);
@@ -233,103 +579,209 @@ impl OscSender {
 
         Self {
             sock,
-            consumer,
+            events,
             dest_addr,
-            led_producer,
-            error_msg_consumer,
-            destip_consumer,
+            duplicate_filter: DuplicateFilter::new(),
+            reported_drops: Vec::new(),
+            reported_node_drops: [0; NODE_ADDRESSES.len()],
+            last_overflow_check: Instant::now(),
+            stats_interval,
+            last_stats: Instant::now(),
         }
     }
 
     /**
-     * Receives message from ESPNOW receiver, dispatches OSC message to upstream
+     * Receives events from ESPNOW receiver and others, dispatches OSC message to upstream
     */
     pub fn run(&mut self) -> Result<()> {
-            if let Some(frame) = self.consumer.read() {
-                // Store device No
-                let mut buf = vec![OscType::Int(frame[1] as i32)];
-                let msg_type = num::FromPrimitive::from_u8(frame[0]);
-
-                let addr_str = match msg_type {
-                    Some(Msg::Mac) => {
-                        for f in frame[2..].iter(){
-                            buf.push(OscType::Int(*f as i32));
-                        }
-                        "/mac".to_string()
-                    }
+        while let Some(event) = self.events.try_recv() {
+            match event {
+                Event::Upstream(data) => self.forward_upstream(&data)?,
+                Event::SendResult { device_no, .. } => self.send_not_found(device_no)?,
+                Event::ConfigChange(ConfigChange::DestIp(dest_ip)) => self.change_dest_ip(dest_ip)?,
+                Event::Denied { src, reason, addr } => self.send_denied(src, reason, &addr)?,
+                Event::Spoof(mac) => self.send_spoof(mac)?,
+                Event::EstopAck(device_no) => self.send_estop_ack(device_no)?,
+                Event::EstopMissing(missing) => self.send_estop_missing(&missing)?,
+                Event::NodeState { device_no, state } => self.send_node_state(device_no, state)?,
+                Event::Query(Query::Nodes) => self.send_nodes()?,
+                Event::Query(Query::Stats) => self.send_stats()?,
+                _ => {}
+            }
+        }
+        if let Some(interval) = self.stats_interval {
+            if self.last_stats.elapsed() >= interval {
+                self.last_stats = Instant::now();
+                self.send_stats()?;
+            }
+        }
+        if self.last_overflow_check.elapsed() >= OVERFLOW_REPORT_INTERVAL {
+            self.last_overflow_check = Instant::now();
+            self.send_overflow()?;
+        }
+        Ok(())
+    }
 
-                    Some(Msg::Boot) => {
-                        "/boot".to_string()
-                    }
+    /**
+     * Convert ESPNOW frame into OSC message. v2 frames are de-duplicated by sequence number.
+    */
+    fn forward_upstream(&mut self, data: &[u8]) -> Result<()> {
+        let (seq, frame) = protocol::decode(data);
+        if frame.len() < 2 {
+            error!("Upstream frame too short: {:02X?}", data);
+            return Ok(());
+        }
+        let device_no = frame[1] as usize;
+        let msg_type = num::FromPrimitive::from_u8(frame[0]);
 
-                    Some(Msg::Status) => {
-                        for f in frame[2..].iter(){
-                            buf.push(OscType::Int(*f as i32));
-                        }
-                        "/status".to_string()
-                    }
+        // Node rebooted, its sequence starts over
+        if let Some(Msg::Boot) = msg_type {
+            self.duplicate_filter.reset(device_no);
+        }
+        if let Some(seq) = seq {
+            if self.duplicate_filter.is_duplicate(device_no, seq) {
+                info!("Duplicate frame from {device_no}, seq:{seq}");
+                return Ok(());
+            }
+        }
 
-                    _ => {
-                        // Append header to the packet for debug
-                        buf.push(OscType::Int(frame[0] as i32));
-                        if frame.len() > 2 {
-                            for f in frame[2..].iter(){
-                                buf.push(OscType::Int(*f as i32));
-                            }
-                        }
-                        "/unknown".to_string()
-                    }
+        // Store device No
+        let mut buf = vec![OscType::Int(frame[1] as i32)];
+
+        let addr_str = match msg_type {
+            Some(Msg::Mac) => {
+                for f in frame[2..].iter(){
+                    buf.push(OscType::Int(*f as i32));
+                }
+                "/mac".to_string()
+            }
+
+            Some(Msg::Boot) => {
+                // Protocol negotiation: [Boot, device no, protocol version, capabilities], legacy nodes omit them
+                let version = frame.get(2).copied().unwrap_or(PROTOCOL_V1);
+                protocol::set_protocol(device_no, version);
+                protocol::set_capabilities(device_no, frame.get(3).copied().unwrap_or(0));
+                info!("Device {device_no} protocol v{}, app ack:{}", protocol::protocol(device_no), protocol::app_ack(device_no));
+                "/boot".to_string()
+            }
+
+            Some(Msg::Ack) => {
+                // [Ack, device no, seq]
+                let ack_seq = match frame.get(2) {
+                    Some(ack_seq) => *ack_seq,
+                    None => return Ok(()),
                 };
-                frame.release();
-
-                // Send OSC message to PC
-                info!("Send {:?} to {:?}  msg:{:X?}", addr_str, self.dest_addr, buf);
-                let msg_buf =
-                    rosc::encoder::encode(&OscPacket::Message(OscMessage {
-                        addr: addr_str,
-                        args: buf,
-                    }))?;
-
-                let ret = self.sock.send_to(&msg_buf, self.dest_addr);
-                match ret {
-                    Ok(_) => {
-                        // Send out led1 indication
-                        if let Ok(mut wg) = self.led_producer.grant(1){
-                            wg.to_commit(1);
-                            wg[0] = 1;
-                            wg.commit(1);
-                        }
+                match protocol::acknowledge(device_no, ack_seq) {
+                    Some(rtt_us) => {
+                        buf.push(OscType::Int(ack_seq as i32));
+                        buf.push(OscType::Int(rtt_us as i32));
                     }
-                    Err(e) => {
-                    bail!("Error sending out osc msg to PC1: {e}");
+                    None => {
+                        // Late ACK for a frame already given up or resent
+                        info!("Unexpected ACK from {device_no}, seq:{ack_seq}");
+                        return Ok(());
                     }
                 }
-            };
+                "/ack".to_string()
+            }
 
-        self.check_espnow_error()?;
-        self.check_dest_ip_change()?;
+            Some(Msg::EstopAck) => {
+                // Reported by the ESPNOW thread as /estop/ack, once every node confirmed
+                bus::publish(Event::Reply(frame.to_vec()));
+                return Ok(());
+            }
+
+            Some(Msg::Status) => {
+                // Answer to a liveness probe, the node is known alive already
+                if liveness::probe_reply(device_no) {
+                    return Ok(());
+                }
+                for f in frame[2..].iter(){
+                    buf.push(OscType::Int(*f as i32));
+                }
+                "/status".to_string()
+            }
+
+            _ => {
+                // Append header to the packet for debug
+                buf.push(OscType::Int(frame[0] as i32));
+                if frame.len() > 2 {
+                    for f in frame[2..].iter(){
+                        buf.push(OscType::Int(*f as i32));
+                    }
+                }
+                "/unknown".to_string()
+            }
+        };
+
+        // Other sinks (MIDI...) get the decoded reply
+        bus::publish(Event::Reply(frame.to_vec()));
+
+        // Destination hostname never resolved, the frame can't be forwarded
+        if self.dest_addr.ip().is_unspecified() {
+            stats::count(&UPSTREAM_DROPPED);
+            return Ok(());
+        }
+
+        // Send OSC message to PC
+        info!("Send {:?} to {:?}  msg:{:X?}", addr_str, self.dest_addr, buf);
+        let msg_buf =
+            rosc::encoder::encode(&OscPacket::Message(OscMessage {
+                addr: addr_str,
+                args: buf,
+            }))?;
 
+        let ret = self.send_buf(&msg_buf);
+        match ret {
+            Ok(_) => {
+                stats::count(&UPSTREAM_FORWARDED);
+                // Send out led1 indication
+                bus::publish(Event::Indicator(Indicator::Osc));
+                info!("Upstream latency {}us", UPSTREAM_LATENCY.elapsed_us());
+            }
+            Err(e) => {
+            bail!("Error sending out osc msg to PC1: {e}");
+            }
+        }
         Ok(())
     }
 
     /**
-     * Sleep until next interval
+     * Send OSC datagram to the destination, recorded when capturing.
+     * Nothing is sent while the destination hostname never resolved.
+    */
+    fn send_buf(&self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.dest_addr.ip().is_unspecified() {
+            return Ok(0);
+        }
+        capture::osc(Direction::Out, self.dest_addr, buf);
+        self.sock.send_to(buf, self.dest_addr)
+    }
+
+    /**
+     * Block until an event is queued for the sender
     */
     pub fn idle(&self) {
-        std::thread::sleep(OSC_LISTEN_INTERVAL_MS);
+        self.events.wait(OSC_SENDER_IDLE_TIMEOUT);
     }
 
     /**
-     *  Send boot msg to the PC with my device number: 0
+     *  Send boot msg to the PC with my device number: 0, IP address and how it was set
+     *  /boot 0 [ip0] [ip1] [ip2] [ip3] [static/dhcp/fallback/wifi]
      */
-    pub fn send_bootmsg(&self) -> Result<()>{
+    pub fn send_bootmsg(&self, local_ip: Ipv4Addr, addressing: &str) -> Result<()>{
+        let mut args = vec![OscType::Int(0)];
+        for b in local_ip.octets() {
+            args.push(OscType::Int(b as i32));
+        }
+        args.push(OscType::String(addressing.to_string()));
         let msg_buf =
         rosc::encoder::encode(&OscPacket::Message(OscMessage {
             addr: "/boot".to_string(),
-            args: vec![OscType::Int(0)],
+            args,
         }))?;
 
-        if let Err(e) = self.sock.send_to(&msg_buf, self.dest_addr)
+        if let Err(e) = self.send_buf(&msg_buf)
         {
             error!("Error sending OSC{e}");
         }
@@ -338,52 +790,217 @@ impl OscSender {
     }
 
     /**
-     * Check buffer for espnow error, if there is an error, send back error OSC msg to PC
+     * Send back error OSC msg to PC when the espnow message did not reach to destination
+     * Missing app level ACK after retries is reported the same way.
     */
-    fn check_espnow_error(&mut self) -> Result<()>{
-        if let Some(frame) = self.error_msg_consumer.read()
+    fn send_not_found(&mut self, dev_no: u8) -> Result<()>{
+        let msg_buf =
+        rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: "/notfound".to_string(),
+            args: vec![OscType::Int(dev_no as i32)],
+        }))?;
+
+        if let Err(e) = self.send_buf(&msg_buf)
         {
-            let dev_no = frame[0];
-            frame.release();
+            error!("Error sending OSC{e}");
+        }
+        Ok(())
+    }
 
-            let msg_buf =
-            rosc::encoder::encode(&OscPacket::Message(OscMessage {
-                addr: "/notfound".to_string(),
-                args: vec![OscType::Int(dev_no as i32)],
-            }))?;
+    fn change_dest_ip(&mut self, dest_ip: Ipv4Addr) -> Result<()>{
+        self.dest_addr.set_ip(dest_ip);
 
-            if let Err(e) = self.sock.send_to(&msg_buf, self.dest_addr)
-            {
-                error!("Error sending OSC{e}");
-            }
+        let ip_addr = self.dest_addr.ip().octets();
+        let msg_buf =
+        rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: "/destip".to_string(),
+            args: vec![OscType::Int(ip_addr[0] as i32), OscType::Int(ip_addr[1] as i32), OscType::Int(ip_addr[2] as i32), OscType::Int(ip_addr[3] as i32)],
+        }))?;
+
+        if let Err(e) = self.send_buf(&msg_buf)
+        {
+            error!("Error sending OSC{e}");
         }
         Ok(())
     }
 
-    fn check_dest_ip_change(&mut self) -> Result<()>{
-        if let Some(frame) = self.destip_consumer.read()
+    /**
+     * Report rejected privileged command: /denied [ip0] [ip1] [ip2] [ip3] [OSC address] [reason]
+    */
+    fn send_denied(&mut self, src: Ipv4Addr, reason: Denied, addr: &str) -> Result<()>{
+        let mut args: Vec<OscType> = src.octets().iter().map(|b| OscType::Int(*b as i32)).collect();
+        args.push(OscType::String(addr.to_string()));
+        args.push(OscType::String(reason.as_str().to_string()));
+
+        let msg_buf =
+        rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: "/denied".to_string(),
+            args,
+        }))?;
+
+        if let Err(e) = self.send_buf(&msg_buf)
+        {
+            error!("Error sending OSC{e}");
+        }
+        Ok(())
+    }
+
+    /**
+     * Report rejected ESPNOW frame: /spoof [mac0] ... [mac5] [total rejected]
+    */
+    fn send_spoof(&mut self, mac: [u8; 6]) -> Result<()>{
+        let mut args: Vec<OscType> = mac.iter().map(|b| OscType::Int(*b as i32)).collect();
+        args.push(OscType::Int(SPOOF_COUNT.load(std::sync::atomic::Ordering::Relaxed) as i32));
+
+        let msg_buf =
+        rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: "/spoof".to_string(),
+            args,
+        }))?;
+
+        if let Err(e) = self.send_buf(&msg_buf)
         {
-            if frame.len() == 4 {
-                let mut newip = [0u8; 4];
-                newip.copy_from_slice(&frame);
-                let dest_ip = Ipv4Addr::from(newip);
-                self.dest_addr.set_ip(dest_ip);
+            error!("Error sending OSC{e}");
+        }
+        Ok(())
+    }
+
+    /**
+     * Node acknowledged the e-stop state: /estop/ack [device no]
+    */
+    fn send_estop_ack(&mut self, device_no: u8) -> Result<()>{
+        self.send_upstream("/estop/ack", vec![OscType::Int(device_no as i32)])
+    }
+
+    /**
+     * Nodes that didn't acknowledge the e-stop state: /estop/missing [device no]...
+    */
+    fn send_estop_missing(&mut self, missing: &[u8]) -> Result<()>{
+        self.send_upstream("/estop/missing", missing.iter().map(|no| OscType::Int(*no as i32)).collect())
+    }
+
+    /**
+     * Node liveness transition: /online [device no] or /offline [device no]
+    */
+    fn send_node_state(&mut self, device_no: u8, state: NodeState) -> Result<()>{
+        let addr = match state {
+            NodeState::Online => "/online",
+            NodeState::Offline => "/offline",
+            NodeState::Unknown => return Ok(()),
+        };
+        self.send_upstream(addr, vec![OscType::Int(device_no as i32)])
+    }
+
+    /**
+     * Answer /nodes: [device no] [state] [ms since last seen, -1 never]... for every node
+    */
+    fn send_nodes(&mut self) -> Result<()>{
+        let mut args = Vec::new();
+        // Broadcast address is not a node
+        for (no, node) in liveness::nodes().iter().enumerate().skip(1) {
+            args.push(OscType::Int(no as i32));
+            args.push(OscType::String(node.state.as_str().to_string()));
+            args.push(OscType::Int(node.last_seen.map_or(-1, |t| t.elapsed().as_millis().min(i32::MAX as u128) as i32)));
+        }
+        self.send_upstream("/nodes", args)
+    }
+
+    /**
+     * Station health, one /stats/... message per group
+    */
+    fn send_stats(&mut self) -> Result<()>{
+        let int = |v: u32| OscType::Int(v as i32);
+        self.send_upstream("/stats/osc", vec![int(stats::get(&OSC_RECEIVED)), int(stats::get(&OSC_DECODED)), int(stats::get(&OSC_FAILED))])?;
+        self.send_upstream("/stats/espnow", vec![
+            int(stats::get(&ESPNOW_SENT)),
+            int(stats::get(&ESPNOW_DELIVERED)),
+            int(stats::get(&ESPNOW_FAILED)),
+            int(stats::get(&ESPNOW_RETRIED)),
+        ])?;
+        self.send_upstream("/stats/upstream", vec![int(stats::get(&UPSTREAM_FORWARDED)), int(stats::get(&UPSTREAM_DROPPED))])?;
+        for queue in bus::stats() {
+            self.send_upstream("/stats/queue", vec![
+                OscType::String(queue.name.to_string()),
+                int(queue.drops),
+                int(queue.high_water),
+                int(queue.capacity as u32),
+            ])?;
+        }
+        for queue in scheduler::stats() {
+            self.send_upstream("/stats/scheduler", vec![
+                int(queue.device_no as u32),
+                int(queue.drops),
+                int(queue.high_water),
+                int(NODE_QUEUE_CAPACITY as u32),
+            ])?;
+        }
+        let (free, min_free) = stats::heap();
+        self.send_upstream("/stats/heap", vec![int(free), int(min_free)])?;
+        self.send_upstream("/stats/uptime", vec![int(stats::uptime_s())])?;
+        self.send_upstream("/stats/eth", vec![int(ETH_UP.load(std::sync::atomic::Ordering::Relaxed) as u32)])?;
+        let mut dest: Vec<OscType> = self.dest_addr.ip().octets().iter().map(|b| int(*b as u32)).collect();
+        dest.push(int(self.dest_addr.port() as u32));
+        self.send_upstream("/stats/dest", dest)
+    }
+
+    fn send_upstream(&mut self, addr: &str, args: Vec<OscType>) -> Result<()>{
+        let msg_buf =
+        rosc::encoder::encode(&OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args,
+        }))?;
+
+        if let Err(e) = self.send_buf(&msg_buf)
+        {
+            error!("Error sending OSC{e}");
+        }
+        Ok(())
+    }
+
+    /**
+     * Report queues that dropped events since the last report: /overflow [queue] [drop count],
+     * /overflow scheduler [device no] [drop count] for the ESPNOW node queues
+    */
+    fn send_overflow(&mut self) -> Result<()> {
+        for stats in bus::stats() {
+            let reported = match self.reported_drops.iter_mut().find(|(name, _)| *name == stats.name) {
+                Some((_, reported)) => reported,
+                None => {
+                    self.reported_drops.push((stats.name, 0));
+                    &mut self.reported_drops.last_mut().unwrap().1
+                }
+            };
+            if stats.drops == *reported {
+                continue;
             }
-            frame.release();
+            *reported = stats.drops;
+            warn!("Queue {} dropped {} events, high water {}/{}", stats.name, stats.drops, stats.high_water, stats.capacity);
 
-            let ip_addr = self.dest_addr.ip().octets();
             let msg_buf =
             rosc::encoder::encode(&OscPacket::Message(OscMessage {
-                addr: "/destip".to_string(),
-                args: vec![OscType::Int(ip_addr[0] as i32), OscType::Int(ip_addr[1] as i32), OscType::Int(ip_addr[2] as i32), OscType::Int(ip_addr[3] as i32)],
+                addr: "/overflow".to_string(),
+                args: vec![OscType::String(stats.name.to_string()), OscType::Int(stats.drops as i32)],
             }))?;
 
-            if let Err(e) = self.sock.send_to(&msg_buf, self.dest_addr)
+            if let Err(e) = self.send_buf(&msg_buf)
             {
                 error!("Error sending OSC{e}");
             }
         }
+        for queue in scheduler::stats() {
+            let reported = &mut self.reported_node_drops[queue.device_no as usize];
+            if queue.drops == *reported {
+                continue;
+            }
+            *reported = queue.drops;
+            warn!("Scheduler queue of {} dropped {} frames, high water {}/{NODE_QUEUE_CAPACITY}", queue.device_no, queue.drops, queue.high_water);
+            self.send_upstream("/overflow", vec![
+                OscType::String("scheduler".to_string()),
+                OscType::Int(queue.device_no as i32),
+                OscType::Int(queue.drops as i32),
+            ])?;
+        }
         Ok(())
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+
+use std::io::ErrorKind;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+/**
+ * Block until one of the descriptors is readable or the timeout, sockets and VFS devices (UART) alike.
+ * Returns a readable flag per descriptor, all false on timeout. Errors and hang-ups count as readable,
+ * the following read reports them.
+*/
+pub fn wait_readable<const N: usize>(fds: [RawFd; N], timeout: Duration) -> Result<[bool; N]> {
+    let mut pollfds = fds.map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+    // Rounded up, so a wait shorter than 1ms doesn't spin
+    let timeout_ms = ((timeout.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32;
+    let ret = unsafe { libc::poll(pollfds.as_mut_ptr(), N as libc::nfds_t, timeout_ms) };
+    if ret < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == ErrorKind::Interrupted {
+            return Ok([false; N]);
+        }
+        return Err(e.into());
+    }
+    Ok(pollfds.map(|p| p.revents & (libc::POLLIN | libc::POLLERR | libc::POLLHUP) != 0))
+}
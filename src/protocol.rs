@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, Ordering};
+
+use crate::espnow::NODE_ADDRESSES;
+
+/*
+ * ESPNOW frame formats
+ * v1 (legacy): [header, device no, payload...]
+ * v2:          [FRAME_V2, seq, header, device no, payload...]
+ * Sequence numbers are per destination. Retries resend the same sequence,
+ * so the receiver can drop frames it already executed.
+*/
+pub const FRAME_V2: u8 = 0xF2;
+pub const PROTOCOL_V1: u8 = 1;
+pub const PROTOCOL_V2: u8 = 2;
+
+// Peer capability flags, sent after the protocol version in the boot message
+// Node answers every v2 frame with [Ack, device no, seq] once executed
+pub const CAP_APP_ACK: u8 = 0x01;
+
+// Max ESPNOW frame handled by the station, including protocol overhead
+pub const ESPNOW_FRAME_LEN: usize = 32;
+
+const NODE_COUNT: usize = NODE_ADDRESSES.len();
+
+// Protocol version of each peer, negotiated by the Boot message or set by /protocol
+#[allow(clippy::declare_interior_mutable_const)]
+const PROTOCOL_INIT: AtomicU8 = AtomicU8::new(PROTOCOL_V1);
+static PEER_PROTOCOL: [AtomicU8; NODE_COUNT] = [PROTOCOL_INIT; NODE_COUNT];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CAPS_INIT: AtomicU8 = AtomicU8::new(0);
+static PEER_CAPS: [AtomicU8; NODE_COUNT] = [CAPS_INIT; NODE_COUNT];
+
+// Sequence sent but not yet acknowledged, OUTSTANDING flag | seq
+const OUTSTANDING: u16 = 0x100;
+#[allow(clippy::declare_interior_mutable_const)]
+const OUTSTANDING_INIT: AtomicU16 = AtomicU16::new(0);
+static OUTSTANDING_SEQ: [AtomicU16; NODE_COUNT] = [OUTSTANDING_INIT; NODE_COUNT];
+// Send time of the outstanding sequence, lower 32 bits of esp_timer in us
+#[allow(clippy::declare_interior_mutable_const)]
+const SENT_AT_INIT: AtomicU32 = AtomicU32::new(0);
+static SENT_AT_US: [AtomicU32; NODE_COUNT] = [SENT_AT_INIT; NODE_COUNT];
+
+fn now_us() -> u32 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u32 }
+}
+
+pub fn protocol(device_no: usize) -> u8 {
+    match PEER_PROTOCOL.get(device_no) {
+        Some(p) => p.load(Ordering::Relaxed),
+        None => PROTOCOL_V1,
+    }
+}
+
+/**
+ * Set peer protocol version. Broadcast peer always uses v1, since every node receives it.
+*/
+pub fn set_protocol(device_no: usize, version: u8) {
+    if device_no == 0 {
+        return;
+    }
+    if let Some(p) = PEER_PROTOCOL.get(device_no) {
+        p.store(version.clamp(PROTOCOL_V1, PROTOCOL_V2), Ordering::Relaxed);
+    }
+}
+
+pub fn capabilities(device_no: usize) -> u8 {
+    match PEER_CAPS.get(device_no) {
+        Some(c) => c.load(Ordering::Relaxed),
+        None => 0,
+    }
+}
+
+pub fn set_capabilities(device_no: usize, caps: u8) {
+    if device_no == 0 {
+        return;
+    }
+    if let Some(c) = PEER_CAPS.get(device_no) {
+        c.store(caps, Ordering::Relaxed);
+    }
+}
+
+/**
+ * App level ACK needs sequence numbers to correlate, so it only applies to v2 peers
+*/
+pub fn app_ack(device_no: usize) -> bool {
+    protocol(device_no) == PROTOCOL_V2 && capabilities(device_no) & CAP_APP_ACK != 0
+}
+
+pub fn mark_outstanding(device_no: usize, seq: u8) {
+    if let Some(o) = OUTSTANDING_SEQ.get(device_no) {
+        SENT_AT_US[device_no].store(now_us(), Ordering::Relaxed);
+        o.store(OUTSTANDING | seq as u16, Ordering::Relaxed);
+    }
+}
+
+/**
+ * Restart the ACK timer of a resent frame, if it is still outstanding
+*/
+pub fn touch_outstanding(device_no: usize, seq: u8) -> bool {
+    if outstanding(device_no) == Some(seq) {
+        SENT_AT_US[device_no].store(now_us(), Ordering::Relaxed);
+        true
+    }
+    else {
+        false
+    }
+}
+
+/**
+ * Microseconds since the outstanding sequence was sent
+*/
+pub fn outstanding_elapsed_us(device_no: usize) -> Option<u32> {
+    outstanding(device_no)?;
+    Some(now_us().wrapping_sub(SENT_AT_US[device_no].load(Ordering::Relaxed)))
+}
+
+/**
+ * App level ACK received. Returns round trip time in us when it matches the outstanding sequence.
+*/
+pub fn acknowledge(device_no: usize, seq: u8) -> Option<u32> {
+    let o = OUTSTANDING_SEQ.get(device_no)?;
+    let expected = OUTSTANDING | seq as u16;
+    if o.compare_exchange(expected, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        Some(now_us().wrapping_sub(SENT_AT_US[device_no].load(Ordering::Relaxed)))
+    }
+    else {
+        None
+    }
+}
+
+pub fn clear_outstanding(device_no: usize) {
+    if let Some(o) = OUTSTANDING_SEQ.get(device_no) {
+        o.store(0, Ordering::Relaxed);
+    }
+}
+
+pub fn outstanding(device_no: usize) -> Option<u8> {
+    let o = OUTSTANDING_SEQ.get(device_no)?.load(Ordering::Relaxed);
+    if o & OUTSTANDING != 0 {
+        Some(o as u8)
+    }
+    else {
+        None
+    }
+}
+
+/**
+ * Downstream sequence numbers, one counter per destination
+*/
+pub struct SeqCounter {
+    next: [u8; NODE_COUNT],
+}
+
+impl SeqCounter {
+    pub fn new() -> Self {
+        Self { next: [0u8; NODE_COUNT] }
+    }
+
+    pub fn next(&mut self, device_no: usize) -> u8 {
+        let seq = self.next[device_no];
+        self.next[device_no] = seq.wrapping_add(1);
+        seq
+    }
+}
+
+/**
+ * Encode v1 frame into the peer's protocol. Returns encoded length and sequence number for v2.
+*/
+pub fn encode(frame: &[u8], seq_counter: &mut SeqCounter, out: &mut [u8; ESPNOW_FRAME_LEN]) -> (usize, Option<u8>) {
+    let device_no = frame[1] as usize;
+    if protocol(device_no) == PROTOCOL_V2 && frame.len() + 2 <= ESPNOW_FRAME_LEN {
+        let seq = seq_counter.next(device_no);
+        out[0] = FRAME_V2;
+        out[1] = seq;
+        out[2..frame.len() + 2].copy_from_slice(frame);
+        (frame.len() + 2, Some(seq))
+    }
+    else {
+        let len = frame.len().min(ESPNOW_FRAME_LEN);
+        out[..len].copy_from_slice(&frame[..len]);
+        (len, None)
+    }
+}
+
+/**
+ * Strip v2 header. Returns sequence number if any, and v1 frame.
+*/
+pub fn decode(data: &[u8]) -> (Option<u8>, &[u8]) {
+    if data.len() >= 4 && data[0] == FRAME_V2 {
+        (Some(data[1]), &data[2..])
+    }
+    else {
+        (None, data)
+    }
+}
+
+/**
+ * Drops upstream frames already received, e.g. when node resent the reply because the ACK was lost
+*/
+pub struct DuplicateFilter {
+    last: [Option<u8>; NODE_COUNT],
+}
+
+impl DuplicateFilter {
+    pub fn new() -> Self {
+        Self { last: [None; NODE_COUNT] }
+    }
+
+    pub fn is_duplicate(&mut self, device_no: usize, seq: u8) -> bool {
+        match self.last.get_mut(device_no) {
+            Some(last) => {
+                if *last == Some(seq) {
+                    true
+                }
+                else {
+                    *last = Some(seq);
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Node rebooted, its sequence starts over
+    */
+    pub fn reset(&mut self, device_no: usize) {
+        if let Some(last) = self.last.get_mut(device_no) {
+            *last = None;
+        }
+    }
+}
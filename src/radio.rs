@@ -0,0 +1,190 @@
+use anyhow::{bail, Result};
+use log::*;
+
+use esp_idf_sys::*;
+
+use crate::config::Config;
+
+// esp_wifi_set_max_tx_power range, in 0.25 dBm
+const TX_POWER_MIN: u8 = 8;
+const TX_POWER_MAX: u8 = 84;
+// Max TX power of the country settings, dBm
+const COUNTRY_MAX_TX_POWER: i8 = 20;
+const PROTOCOL_DEFAULT: u32 = WIFI_PROTOCOL_11B | WIFI_PROTOCOL_11G | WIFI_PROTOCOL_11N;
+
+/*
+ * Settings persisted in NVS
+ * [channel, TX power (0.25 dBm, 0 default), phy (0 default / 1 LR / 2 fixed rate), rate index, country code (2 bytes, 0 none)]
+*/
+pub const RADIO_SETTINGS_LEN: usize = 6;
+
+// ESP-NOW rates, long GI for MCS
+const RATES: [(&str, wifi_phy_rate_t); 20] = [
+    ("1m", wifi_phy_rate_t_WIFI_PHY_RATE_1M_L),
+    ("2m", wifi_phy_rate_t_WIFI_PHY_RATE_2M_L),
+    ("5m", wifi_phy_rate_t_WIFI_PHY_RATE_5M_L),
+    ("11m", wifi_phy_rate_t_WIFI_PHY_RATE_11M_L),
+    ("6m", wifi_phy_rate_t_WIFI_PHY_RATE_6M),
+    ("9m", wifi_phy_rate_t_WIFI_PHY_RATE_9M),
+    ("12m", wifi_phy_rate_t_WIFI_PHY_RATE_12M),
+    ("18m", wifi_phy_rate_t_WIFI_PHY_RATE_18M),
+    ("24m", wifi_phy_rate_t_WIFI_PHY_RATE_24M),
+    ("36m", wifi_phy_rate_t_WIFI_PHY_RATE_36M),
+    ("48m", wifi_phy_rate_t_WIFI_PHY_RATE_48M),
+    ("54m", wifi_phy_rate_t_WIFI_PHY_RATE_54M),
+    ("mcs0", wifi_phy_rate_t_WIFI_PHY_RATE_MCS0_LGI),
+    ("mcs1", wifi_phy_rate_t_WIFI_PHY_RATE_MCS1_LGI),
+    ("mcs2", wifi_phy_rate_t_WIFI_PHY_RATE_MCS2_LGI),
+    ("mcs3", wifi_phy_rate_t_WIFI_PHY_RATE_MCS3_LGI),
+    ("mcs4", wifi_phy_rate_t_WIFI_PHY_RATE_MCS4_LGI),
+    ("mcs5", wifi_phy_rate_t_WIFI_PHY_RATE_MCS5_LGI),
+    ("mcs6", wifi_phy_rate_t_WIFI_PHY_RATE_MCS6_LGI),
+    ("mcs7", wifi_phy_rate_t_WIFI_PHY_RATE_MCS7_LGI),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phy {
+    Default,
+    // 802.11 LR only, nodes have to use LR too
+    LongRange,
+    // Fixed ESP-NOW rate, index in RATES
+    Rate(u8),
+}
+
+impl Phy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "default" => Some(Phy::Default),
+            "lr" => Some(Phy::LongRange),
+            s => RATES.iter().position(|(name, _)| *name == s).map(|i| Phy::Rate(i as u8)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phy::Default => "default",
+            Phy::LongRange => "lr",
+            Phy::Rate(i) => RATES[*i as usize].0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RadioSettings {
+    // AP and ESP-NOW peer channel, 0 for the driver's default
+    pub channel: u8,
+    // 0.25 dBm, 0 for the driver's default
+    pub tx_power: u8,
+    pub phy: Phy,
+    pub country: Option<[u8; 2]>,
+}
+
+impl RadioSettings {
+    /**
+     * Settings from NVS, the build's ESPNOW_CHANNEL when there are none
+    */
+    pub fn load(config: &Config, default_channel: u8) -> Self {
+        let default = Self { channel: default_channel, tx_power: 0, phy: Phy::Default, country: None };
+        match config.radio_settings().and_then(|b| Self::from_bytes(&b)) {
+            Some(settings) => settings,
+            None => default,
+        }
+    }
+
+    pub fn from_bytes(b: &[u8; RADIO_SETTINGS_LEN]) -> Option<Self> {
+        let phy = match b[2] {
+            0 => Phy::Default,
+            1 => Phy::LongRange,
+            2 if (b[3] as usize) < RATES.len() => Phy::Rate(b[3]),
+            _ => return None,
+        };
+        let country = if b[4] == 0 { None } else { Some([b[4], b[5]]) };
+        let settings = Self { channel: b[0], tx_power: b[1], phy, country };
+        settings.validate().ok()?;
+        Some(settings)
+    }
+
+    pub fn to_bytes(&self) -> [u8; RADIO_SETTINGS_LEN] {
+        let (phy, rate) = match self.phy {
+            Phy::Default => (0, 0),
+            Phy::LongRange => (1, 0),
+            Phy::Rate(i) => (2, i),
+        };
+        let country = self.country.unwrap_or([0, 0]);
+        [self.channel, self.tx_power, phy, rate, country[0], country[1]]
+    }
+
+    /**
+     * Channel allowed in the country, TX power in the driver's range
+    */
+    pub fn validate(&self) -> Result<()> {
+        let max_channel = max_channel(self.country);
+        if self.channel > max_channel {
+            bail!("Channel {} out of 1-{max_channel}", self.channel);
+        }
+        if self.tx_power != 0 && !(TX_POWER_MIN..=TX_POWER_MAX).contains(&self.tx_power) {
+            bail!("TX power {} dBm out of {}-{} dBm", self.tx_power as f32 / 4.0, TX_POWER_MIN / 4, TX_POWER_MAX / 4);
+        }
+        if let Some(cc) = self.country {
+            if !cc.iter().all(|c| c.is_ascii_uppercase()) {
+                bail!("Bad country code {:?}", cc);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Apply country, TX power and PHY to the started WiFi driver. The channel is set with the AP configuration.
+    */
+    pub fn apply(&self) -> Result<()> {
+        if let Some(cc) = self.country {
+            let country = wifi_country_t {
+                // Third character ' ': all environments
+                cc: [cc[0] as _, cc[1] as _, b' ' as _],
+                schan: 1,
+                nchan: max_channel(self.country),
+                max_tx_power: COUNTRY_MAX_TX_POWER,
+                policy: wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
+            };
+            esp!(unsafe { esp_wifi_set_country(&country) })?;
+        }
+        if self.tx_power != 0 {
+            esp!(unsafe { esp_wifi_set_max_tx_power(self.tx_power as i8) })?;
+        }
+        match self.phy {
+            Phy::Default => {
+                esp!(unsafe { esp_wifi_set_protocol(wifi_interface_t_WIFI_IF_AP, PROTOCOL_DEFAULT as u8) })?;
+            }
+            Phy::LongRange => {
+                esp!(unsafe { esp_wifi_set_protocol(wifi_interface_t_WIFI_IF_AP, WIFI_PROTOCOL_LR as u8) })?;
+            }
+            Phy::Rate(i) => {
+                esp!(unsafe { esp_wifi_set_protocol(wifi_interface_t_WIFI_IF_AP, PROTOCOL_DEFAULT as u8) })?;
+                esp!(unsafe { esp_wifi_config_espnow_rate(wifi_interface_t_WIFI_IF_AP, RATES[i as usize].1) })?;
+            }
+        }
+        info!("Radio: {:?}", self);
+        Ok(())
+    }
+}
+
+/**
+ * Highest channel of the country, 13 unless known otherwise
+*/
+fn max_channel(country: Option<[u8; 2]>) -> u8 {
+    match country.as_ref().map(|cc| &cc[..]) {
+        Some(b"US") | Some(b"CA") | Some(b"TW") => 11,
+        Some(b"JP") => 14,
+        _ => 13,
+    }
+}
+
+/**
+ * Current primary channel of the WiFi driver
+*/
+pub fn channel() -> Result<u8> {
+    let mut channel = 0u8;
+    let mut second = wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
+    esp!(unsafe { esp_wifi_get_channel(&mut channel, &mut second) })?;
+    Ok(channel)
+}
@@ -0,0 +1,209 @@
+use anyhow::{bail, Result};
+use log::*;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::osc::Msg;
+use crate::bus::Priority;
+use crate::espnow::NODE_ADDRESSES;
+
+// Shortest time between two frames to the same node
+const NODE_MIN_GAP: Duration = Duration::from_millis(10);
+// Frames waiting for each node, one-shot commands beyond that are dropped
+pub const NODE_QUEUE_CAPACITY: usize = 16;
+
+// Frames dropped from each node queue and the most it held, read for /overflow and /stats
+#[allow(clippy::declare_interior_mutable_const)]
+const QUEUE_COUNTER_INIT: AtomicU32 = AtomicU32::new(0);
+static QUEUE_DROPS: [AtomicU32; NODE_ADDRESSES.len()] = [QUEUE_COUNTER_INIT; NODE_ADDRESSES.len()];
+static QUEUE_HIGH_WATER: [AtomicU32; NODE_ADDRESSES.len()] = [QUEUE_COUNTER_INIT; NODE_ADDRESSES.len()];
+
+pub struct NodeQueueStats {
+    pub device_no: u8,
+    pub drops: u32,
+    pub high_water: u32,
+}
+
+pub fn stats() -> Vec<NodeQueueStats> {
+    (0..NODE_ADDRESSES.len()).map(|no| NodeQueueStats {
+        device_no: no as u8,
+        drops: QUEUE_DROPS[no].load(Ordering::Relaxed),
+        high_water: QUEUE_HIGH_WATER[no].load(Ordering::Relaxed),
+    }).collect()
+}
+
+// Share of the airtime ESPNOW downstream may use, and the burst allowed above it
+const AIRTIME_BUDGET_PERCENT: i64 = 50;
+const AIRTIME_BURST_US: i64 = 20_000;
+// Estimated airtime of a frame at 1Mbps: preamble, ACK and interframe spaces, then vendor action frame header and payload
+const AIRTIME_FIXED_US: i64 = 600;
+const AIRTIME_HEADER_LEN: i64 = 43;
+const AIRTIME_PER_BYTE_US: i64 = 8;
+
+fn airtime_us(len: usize) -> i64 {
+    AIRTIME_FIXED_US + (AIRTIME_HEADER_LEN + len as i64) * AIRTIME_PER_BYTE_US
+}
+
+/**
+ * Frames that replace a queued frame with the same key, only the latest value matters.
+ * Param is keyed by param no, so different params of a node are all delivered.
+ * One-shot commands (Reset, Run...) are never coalesced.
+*/
+fn coalesce_key(frame: &[u8]) -> Option<(u8, u8)> {
+    match num::FromPrimitive::from_u8(frame[0]) {
+        Some(Msg::Param) => Some((frame[0], frame.get(2).copied().unwrap_or(0))),
+        Some(Msg::Dmx) | Some(Msg::StatusQuery) | Some(Msg::MacQuery) => Some((frame[0], 0)),
+        _ => None,
+    }
+}
+
+struct NodeQueue {
+    frames: VecDeque<Vec<u8>>,
+    last_sent: Option<Instant>,
+}
+
+impl NodeQueue {
+    fn due(&self, now: Instant) -> Duration {
+        match self.last_sent {
+            Some(last) => NODE_MIN_GAP.saturating_sub(now.duration_since(last)),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/**
+ * Downstream scheduler of the ESPNOW thread.
+ * Keeps a queue per device no, sends round robin with a minimum gap per node and within the airtime budget.
+ * High priority frames are sent first, in arrival order, without gap, budget or coalescing.
+*/
+pub struct Scheduler {
+    urgent: VecDeque<Vec<u8>>,
+    nodes: Vec<NodeQueue>,
+    next_node: usize,
+    // Airtime budget left, token bucket refilled at AIRTIME_BUDGET_PERCENT of elapsed time
+    tokens_us: i64,
+    refilled: Instant,
+}
+
+impl Scheduler {
+    pub fn new(node_count: usize) -> Self {
+        Self {
+            urgent: VecDeque::new(),
+            nodes: (0..node_count).map(|_| NodeQueue { frames: VecDeque::new(), last_sent: None }).collect(),
+            next_node: 0,
+            tokens_us: AIRTIME_BURST_US,
+            refilled: Instant::now(),
+        }
+    }
+
+    /**
+     * Queue v1 frame [header, device no, payload...], replacing a queued frame of the same kind
+    */
+    pub fn push(&mut self, frame: Vec<u8>, priority: Priority) -> Result<()> {
+        if frame.len() < 2 {
+            bail!("Downstream frame too short: {:02X?}", frame);
+        }
+        let target_no = frame[1] as usize;
+        let node = match self.nodes.get_mut(target_no) {
+            Some(node) => node,
+            None => bail!("This device does not exists! {target_no}"),
+        };
+
+        if priority == Priority::High {
+            self.urgent.push_back(frame);
+            return Ok(());
+        }
+
+        if let Some(key) = coalesce_key(&frame) {
+            if let Some(queued) = node.frames.iter_mut().find(|f| coalesce_key(f) == Some(key)) {
+                debug!("Scheduler: coalesced {:02X?} to {target_no}", key);
+                *queued = frame;
+                return Ok(());
+            }
+        }
+        if node.frames.len() >= NODE_QUEUE_CAPACITY {
+            let drops = QUEUE_DROPS.get(target_no).map_or(0, |d| d.fetch_add(1, Ordering::Relaxed) + 1);
+            bail!("Scheduler: queue of {target_no} full, frame dropped ({drops})");
+        }
+        node.frames.push_back(frame);
+        if let Some(high_water) = QUEUE_HIGH_WATER.get(target_no) {
+            high_water.fetch_max(node.frames.len() as u32, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_micros() as i64;
+        self.refilled = now;
+        self.tokens_us = (self.tokens_us + elapsed * AIRTIME_BUDGET_PERCENT / 100).min(AIRTIME_BURST_US);
+    }
+
+    /**
+     * Next high priority frame, it still counts for the gap of its node
+    */
+    pub fn pop_urgent(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let frame = self.urgent.pop_front()?;
+        self.nodes[frame[1] as usize].last_sent = Some(now);
+        Some(frame)
+    }
+
+    /**
+     * Next frame to send, None when no node is due or the airtime budget is used up
+    */
+    pub fn pop(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if let Some(frame) = self.pop_urgent(now) {
+            return Some(frame);
+        }
+        self.refill(now);
+        if self.tokens_us <= 0 {
+            return None;
+        }
+        let count = self.nodes.len();
+        for i in 0..count {
+            let no = (self.next_node + i) % count;
+            let node = &mut self.nodes[no];
+            if node.frames.is_empty() || node.due(now) > Duration::ZERO {
+                continue;
+            }
+            node.last_sent = Some(now);
+            self.next_node = (no + 1) % count;
+            return node.frames.pop_front();
+        }
+        None
+    }
+
+    /**
+     * Drop the normal frames still queued, high priority frames are kept
+    */
+    pub fn clear(&mut self) {
+        for node in self.nodes.iter_mut() {
+            node.frames.clear();
+        }
+    }
+
+    /**
+     * Account the airtime of a sent frame, retries included
+    */
+    pub fn charge(&mut self, len: usize) {
+        self.tokens_us -= airtime_us(len);
+    }
+
+    /**
+     * Time until the next queued frame may be sent, None when nothing is queued
+    */
+    pub fn next_due(&mut self, now: Instant) -> Option<Duration> {
+        if !self.urgent.is_empty() {
+            return Some(Duration::ZERO);
+        }
+        self.refill(now);
+        let node_due = self.nodes.iter().filter(|n| !n.frames.is_empty()).map(|n| n.due(now)).min()?;
+        let budget_due = if self.tokens_us > 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_micros(((1 - self.tokens_us) * 100 / AIRTIME_BUDGET_PERCENT) as u64)
+        };
+        Some(node_due.max(budget_due))
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, KEY_LEN};
+use crate::espnow::NODE_ADDRESSES;
+use crate::protocol::{self, ESPNOW_FRAME_LEN};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/*
+ * Signed frame: [frame..., counter (4 bytes BE), tag (4 bytes)]
+ * tag is HMAC-SHA256 over [receiver MAC (downstream) or sender MAC (upstream), frame..., counter], truncated.
+ * Counter has to increase on every frame, so recorded frames can't be replayed.
+ * Retries resend the same bytes, so the node also drops a retry of a frame it already received.
+*/
+const COUNTER_LEN: usize = 4;
+const TAG_LEN: usize = 4;
+pub const SIGNATURE_LEN: usize = COUNTER_LEN + TAG_LEN;
+
+// Downstream counter is persisted in blocks, so it keeps increasing over reboots without writing NVS on every frame
+const COUNTER_BLOCK: u32 = 1024;
+// Upstream counter of each node is saved when it passed the saved one by a block.
+// After a reboot, only frames within the last block can be replayed.
+const RX_COUNTER_BLOCK: u32 = 64;
+
+const NODE_COUNT: usize = NODE_ADDRESSES.len();
+
+// Signing keys of the peers, copied from NVS so the receive callback doesn't touch flash.
+// Held in atomics, the receive callback never waits for a lock. A frame checked while the key
+// changes may see a mix of both keys and is rejected, as it would be with either key alone.
+struct PeerKey {
+    set: AtomicBool,
+    words: [AtomicU32; KEY_LEN / 4],
+}
+#[allow(clippy::declare_interior_mutable_const)]
+const KEY_WORD_INIT: AtomicU32 = AtomicU32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const PEER_KEY_INIT: PeerKey = PeerKey { set: AtomicBool::new(false), words: [KEY_WORD_INIT; KEY_LEN / 4] };
+static PEER_KEYS: [PeerKey; NODE_COUNT] = [PEER_KEY_INIT; NODE_COUNT];
+// Last accepted upstream counter of each peer
+#[allow(clippy::declare_interior_mutable_const)]
+const RX_COUNTER_INIT: AtomicU32 = AtomicU32::new(0);
+static RX_COUNTER: [AtomicU32; NODE_COUNT] = [RX_COUNTER_INIT; NODE_COUNT];
+// Last upstream counter of each peer saved in NVS
+static RX_SAVED: [AtomicU32; NODE_COUNT] = [RX_COUNTER_INIT; NODE_COUNT];
+// Number of rejected upstream frames
+pub static SPOOF_COUNT: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub enum Rejected {
+    Unsigned,
+    BadTag,
+    Replay,
+    // Signed by a peer, claiming another device no
+    WrongDevice,
+}
+
+/**
+ * Load signing key and saved upstream counter of the peer from config, None disables signing for it.
+ * The counter in RAM never goes back, it may be ahead of the saved one.
+*/
+pub fn load_key(device_no: usize, config: &Config) {
+    if device_no == 0 || device_no >= NODE_COUNT {
+        return;
+    }
+    store_key(device_no, config.sig_key(device_no));
+    let saved = config.rx_counter(device_no);
+    RX_SAVED[device_no].fetch_max(saved, Ordering::Relaxed);
+    RX_COUNTER[device_no].fetch_max(saved, Ordering::Relaxed);
+}
+
+/**
+ * Load signing key changed over OSC. A new key starts the upstream counter over.
+*/
+pub fn reload_key(device_no: usize, config: &mut Config) -> Result<()> {
+    if device_no == 0 || device_no >= NODE_COUNT {
+        return Ok(());
+    }
+    let key = config.sig_key(device_no);
+    if peer_key(device_no) != key {
+        store_key(device_no, key);
+        RX_COUNTER[device_no].store(0, Ordering::Relaxed);
+        RX_SAVED[device_no].store(0, Ordering::Relaxed);
+        config.set_rx_counter(device_no, 0)?;
+    }
+    Ok(())
+}
+
+/**
+ * Save upstream counters that moved a block ahead of the saved ones.
+ * Called from the ESPNOW thread, the receive callback doesn't touch flash.
+*/
+pub fn save_rx_counters(config: &Mutex<Config>) -> Result<()> {
+    for no in 1..NODE_COUNT {
+        let count = RX_COUNTER[no].load(Ordering::Relaxed);
+        if count >= RX_SAVED[no].load(Ordering::Relaxed).saturating_add(RX_COUNTER_BLOCK) {
+            config.lock().unwrap().set_rx_counter(no, count)?;
+            RX_SAVED[no].store(count, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+fn peer_key(device_no: usize) -> Option<[u8; KEY_LEN]> {
+    let peer = PEER_KEYS.get(device_no)?;
+    if !peer.set.load(Ordering::Acquire) {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (chunk, word) in key.chunks_mut(4).zip(peer.words.iter()) {
+        chunk.copy_from_slice(&word.load(Ordering::Relaxed).to_be_bytes());
+    }
+    Some(key)
+}
+
+/**
+ * Key words are written before the key is marked set, so a peer never looks unsigned while its key changes
+*/
+fn store_key(device_no: usize, key: Option<[u8; KEY_LEN]>) {
+    let peer = &PEER_KEYS[device_no];
+    match key {
+        Some(key) => {
+            for (chunk, word) in key.chunks(4).zip(peer.words.iter()) {
+                word.store(u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]), Ordering::Relaxed);
+            }
+            peer.set.store(true, Ordering::Release);
+        }
+        None => peer.set.store(false, Ordering::Release),
+    }
+}
+
+fn tag(key: &[u8; KEY_LEN], mac: &[u8], data: &[u8]) -> [u8; TAG_LEN] {
+    let mut hmac = HmacSha256::new_from_slice(key).unwrap();
+    hmac.update(mac);
+    hmac.update(data);
+    let full = hmac.finalize().into_bytes();
+    let mut tag = [0u8; TAG_LEN];
+    tag.copy_from_slice(&full[..TAG_LEN]);
+    tag
+}
+
+/**
+ * Append counter and tag when the peer has a signing key. Returns new length.
+*/
+pub fn sign(device_no: usize, data: &mut [u8; ESPNOW_FRAME_LEN], len: usize, counter: &mut TxCounter) -> Result<usize> {
+    let key = match peer_key(device_no) {
+        Some(key) => key,
+        None => return Ok(len),
+    };
+    if len + SIGNATURE_LEN > ESPNOW_FRAME_LEN {
+        anyhow::bail!("Frame too long to sign: {len}");
+    }
+    let count = counter.next()?;
+    data[len..len + COUNTER_LEN].copy_from_slice(&count.to_be_bytes());
+    let tag = tag(&key, &NODE_ADDRESSES[device_no], &data[..len + COUNTER_LEN]);
+    data[len + COUNTER_LEN..len + SIGNATURE_LEN].copy_from_slice(&tag);
+    Ok(len + SIGNATURE_LEN)
+}
+
+/**
+ * Verify upstream frame from the sender (device no of its MAC, 0 if unknown).
+ * Returns length without signature.
+ * Unsigned frames are accepted unless the sender, or the device it claims to be, has a signing key.
+ * Signed frames have to carry the sender's own device no.
+*/
+pub fn verify(sender_no: usize, mac: &[u8], data: &[u8]) -> Result<usize, Rejected> {
+    let key = match peer_key(sender_no) {
+        Some(key) => key,
+        None => {
+            let (_, frame) = protocol::decode(data);
+            let claimed_no = frame.get(1).copied().unwrap_or(0) as usize;
+            if claimed_no != sender_no && peer_key(claimed_no).is_some() {
+                return Err(Rejected::Unsigned);
+            }
+            return Ok(data.len());
+        }
+    };
+
+    if data.len() < SIGNATURE_LEN + 2 {
+        return Err(Rejected::Unsigned);
+    }
+    let len = data.len() - SIGNATURE_LEN;
+    let expected = tag(&key, mac, &data[..len + COUNTER_LEN]);
+    // Compare every byte, so timing doesn't leak the matching prefix
+    if expected.iter().zip(data[len + COUNTER_LEN..].iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(Rejected::BadTag);
+    }
+    // A node's key only vouches for the node itself
+    let (_, frame) = protocol::decode(&data[..len]);
+    if frame.get(1).copied().unwrap_or(0) as usize != sender_no {
+        return Err(Rejected::WrongDevice);
+    }
+
+    let mut count = [0u8; COUNTER_LEN];
+    count.copy_from_slice(&data[len..len + COUNTER_LEN]);
+    let count = u32::from_be_bytes(count);
+    let last = &RX_COUNTER[sender_no];
+    if count <= last.load(Ordering::Relaxed) {
+        return Err(Rejected::Replay);
+    }
+    last.store(count, Ordering::Relaxed);
+    Ok(len)
+}
+
+/**
+ * Downstream frame counter, shared by all peers
+*/
+pub struct TxCounter {
+    next: u32,
+    reserved: u32,
+    config: Arc<Mutex<Config>>,
+}
+
+impl TxCounter {
+    pub fn new(config: Arc<Mutex<Config>>) -> Result<Self> {
+        let stored = config.lock().unwrap().sig_counter();
+        let mut counter = Self { next: stored.max(1), reserved: stored, config };
+        counter.reserve()?;
+        Ok(counter)
+    }
+
+    fn reserve(&mut self) -> Result<()> {
+        self.reserved = self.next.saturating_add(COUNTER_BLOCK);
+        self.config.lock().unwrap().set_sig_counter(self.reserved)?;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<u32> {
+        if self.next >= self.reserved {
+            self.reserve()?;
+        }
+        let count = self.next;
+        self.next += 1;
+        Ok(count)
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// OSC packets from the network: received, decoded and failed to decode
+pub static OSC_RECEIVED: AtomicU32 = AtomicU32::new(0);
+pub static OSC_DECODED: AtomicU32 = AtomicU32::new(0);
+pub static OSC_FAILED: AtomicU32 = AtomicU32::new(0);
+
+// ESPNOW frames: sent (retries included), delivered at MAC level, failed after retries, and resent
+pub static ESPNOW_SENT: AtomicU32 = AtomicU32::new(0);
+pub static ESPNOW_DELIVERED: AtomicU32 = AtomicU32::new(0);
+pub static ESPNOW_FAILED: AtomicU32 = AtomicU32::new(0);
+pub static ESPNOW_RETRIED: AtomicU32 = AtomicU32::new(0);
+
+// Node frames sent out as OSC, and dropped while the destination hostname is unresolved
+pub static UPSTREAM_FORWARDED: AtomicU32 = AtomicU32::new(0);
+pub static UPSTREAM_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// Ethernet link, updated from the system event loop
+pub static ETH_UP: AtomicBool = AtomicBool::new(false);
+
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn get(counter: &AtomicU32) -> u32 {
+    counter.load(Ordering::Relaxed)
+}
+
+pub fn uptime_s() -> u32 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u32
+}
+
+/**
+ * Free heap and the lowest it has been since boot, in bytes
+*/
+pub fn heap() -> (u32, u32) {
+    unsafe { (esp_idf_sys::esp_get_free_heap_size(), esp_idf_sys::esp_get_minimum_free_heap_size()) }
+}
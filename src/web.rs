@@ -0,0 +1,32 @@
+use anyhow::Result;
+
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+
+use crate::capture;
+
+pub const HTTP_PORT: u16 = 80;
+
+/**
+ * HTTP server of the station, keep it alive for the handlers to run
+ * GET /capture.pcap downloads the capture buffer
+*/
+pub fn start() -> Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: HTTP_PORT,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/capture.pcap", Method::Get, |request| {
+        let pcap = capture::pcap();
+        let mut response = request.into_response(200, None, &[
+            ("Content-Type", "application/vnd.tcpdump.pcap"),
+            ("Content-Disposition", "attachment; filename=\"capture.pcap\""),
+        ])?;
+        response.write_all(&pcap)?;
+        Ok(())
+    })?;
+
+    Ok(server)
+}
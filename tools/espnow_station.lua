@@ -0,0 +1,99 @@
+-- Wireshark dissector for the station's capture (pcap link type USER0, 147)
+-- Install: copy into the Wireshark personal Lua plugins folder (Help > About > Folders)
+--
+-- Record: [version, kind, direction, status, address (6 bytes), payload...]
+-- kind 0: OSC, address is IPv4 + port. kind 1: ESP-NOW, address is the peer MAC.
+
+local station = Proto("espnow_station", "ESPNOW OSC Station capture")
+
+local kinds = { [0] = "OSC", [1] = "ESP-NOW" }
+local directions = { [0] = "In", [1] = "Out" }
+local statuses = { [0] = "-", [1] = "Delivered", [2] = "Failed", [3] = "Rejected" }
+local messages = {
+    [0x41] = "Ack", [0x42] = "Boot", [0x4D] = "Mac", [0x53] = "EstopAck", [0x55] = "Status",
+    [0x62] = "Reset", [0x64] = "Dmx", [0x6C] = "EstopRelease", [0x6D] = "MacQuery",
+    [0x70] = "Param", [0x72] = "Run", [0x73] = "Estop", [0x75] = "StatusQuery",
+}
+
+local f = station.fields
+f.version = ProtoField.uint8("espnow_station.version", "Version")
+f.kind = ProtoField.uint8("espnow_station.kind", "Kind", base.DEC, kinds)
+f.direction = ProtoField.uint8("espnow_station.direction", "Direction", base.DEC, directions)
+f.status = ProtoField.uint8("espnow_station.status", "Send status", base.DEC, statuses)
+f.mac = ProtoField.ether("espnow_station.mac", "Peer MAC")
+f.ip = ProtoField.ipv4("espnow_station.ip", "OSC address")
+f.port = ProtoField.uint16("espnow_station.port", "OSC port")
+f.seq = ProtoField.uint8("espnow_station.seq", "Sequence (v2)")
+f.msg = ProtoField.uint8("espnow_station.msg", "Message", base.HEX, messages)
+f.device = ProtoField.uint8("espnow_station.device", "Device No")
+f.payload = ProtoField.bytes("espnow_station.payload", "Payload")
+
+local FRAME_V2 = 0xF2
+
+-- Wireshark's own OSC dissector, when available
+local ok, osc_dissector = pcall(Dissector.get, "osc")
+if not ok then
+    osc_dissector = nil
+end
+
+local function dissect_espnow(buf, pinfo, tree)
+    if buf:len() == 0 then
+        return
+    end
+    local offset = 0
+    if buf:len() >= 4 and buf(0, 1):uint() == FRAME_V2 then
+        tree:add(f.seq, buf(1, 1))
+        offset = 2
+    end
+    if buf:len() < offset + 2 then
+        tree:add(f.payload, buf(offset))
+        return
+    end
+    local msg = buf(offset, 1):uint()
+    tree:add(f.msg, buf(offset, 1))
+    tree:add(f.device, buf(offset + 1, 1))
+    -- Signed frames end with counter and tag, shown as part of the payload
+    if buf:len() > offset + 2 then
+        tree:add(f.payload, buf(offset + 2))
+    end
+    pinfo.cols.info:append(string.format(" %s to/from %d", messages[msg] or string.format("0x%02X", msg), buf(offset + 1, 1):uint()))
+end
+
+function station.dissector(buf, pinfo, root)
+    if buf:len() < 10 then
+        return 0
+    end
+    pinfo.cols.protocol = "Station"
+    local kind = buf(1, 1):uint()
+    local direction = buf(2, 1):uint()
+    local status = buf(3, 1):uint()
+    local tree = root:add(station, buf(0, 10))
+    tree:add(f.version, buf(0, 1))
+    tree:add(f.kind, buf(1, 1))
+    tree:add(f.direction, buf(2, 1))
+    tree:add(f.status, buf(3, 1))
+    pinfo.cols.info = string.format("%s %s", kinds[kind] or "?", directions[direction] or "?")
+
+    local payload = ByteArray.new():tvb("Payload")
+    if buf:len() > 10 then
+        payload = buf(10):tvb()
+    end
+    if kind == 0 then
+        tree:add(f.ip, buf(4, 4))
+        tree:add(f.port, buf(8, 2))
+        if payload:len() > 0 and osc_dissector ~= nil then
+            osc_dissector:call(payload, pinfo, root)
+        elseif payload:len() > 0 then
+            tree:add(f.payload, payload())
+        end
+    else
+        tree:add(f.mac, buf(4, 6))
+        if status ~= 0 then
+            pinfo.cols.info:append(" " .. (statuses[status] or "?"))
+        end
+        dissect_espnow(payload, pinfo, tree)
+    end
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, station)